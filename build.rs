@@ -5,11 +5,13 @@ use std::path::Path;
 use std::env;
 
 fn main() {
-    if !Path::new("rocksdb/.git").exists() {
-        assert!(Command::new("git").args(&["submodule", "update", "--init"]).status().unwrap().success());
+    if env::var_os("CARGO_FEATURE_ROCKSDB").is_some() {
+        if !Path::new("rocksdb/.git").exists() {
+            assert!(Command::new("git").args(&["submodule", "update", "--init"]).status().unwrap().success());
+        }
+        assert!(Command::new("make").arg("shared_lib").env("PORTABLE", "1").current_dir("rocksdb").status().unwrap().success());
+        println!("cargo:rustc-link-search=rocksdb");
     }
-    assert!(Command::new("make").arg("shared_lib").env("PORTABLE", "1").current_dir("rocksdb").status().unwrap().success());
-    println!("cargo:rustc-link-search=rocksdb");
 
     let out_dir = env::var_os("OUT_DIR").unwrap();
     let src = Path::new("src/serde_types.in.rs");
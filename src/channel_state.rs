@@ -0,0 +1,313 @@
+use irc::ChatEvent;
+use std::collections::HashMap;
+
+/// Member status prefixes, highest first.
+pub const MEMBER_PREFIXES: &'static str = "~&@%+";
+
+pub fn mode_prefix(mode: char) -> Option<char> {
+    match mode {
+        'q' => Some('~'),
+        'a' => Some('&'),
+        'o' => Some('@'),
+        'h' => Some('%'),
+        'v' => Some('+'),
+        _ => None,
+    }
+}
+
+fn rank(prefix: char) -> usize {
+    MEMBER_PREFIXES.find(prefix).unwrap_or(MEMBER_PREFIXES.len())
+}
+
+/// Channel modes by when they take an argument, as ISUPPORT CHANMODES
+/// classes them; the defaults are the RFC 2811 ones.
+#[derive(Debug)]
+struct ModeTypes {
+    always: String,
+    when_set: String,
+}
+
+impl Default for ModeTypes {
+    fn default() -> ModeTypes {
+        ModeTypes { always: "beIk".to_owned(), when_set: "l".to_owned() }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ChannelState {
+    nick: Option<String>,
+    modes: ModeTypes,
+    channels: HashMap<String, HashMap<String, String>>,
+    names: HashMap<String, HashMap<String, String>>,
+}
+
+impl ChannelState {
+    pub fn new() -> ChannelState {
+        ChannelState::default()
+    }
+
+    pub fn update(&mut self, event: &ChatEvent) {
+        match *event {
+            ChatEvent::Connected { ref nick } => {
+                *self = ChannelState::new();
+                self.nick = Some(nick.clone());
+            },
+            ChatEvent::Disconnected => *self = ChannelState::new(),
+            ChatEvent::ServerSupport { ref tokens } => {
+                for token in tokens.iter().filter(|t| t.starts_with("CHANMODES=")) {
+                    let types: Vec<&str> = token["CHANMODES=".len()..].split(',').collect();
+                    if types.len() >= 3 {
+                        self.modes = ModeTypes { always: format!("{}{}", types[0], types[1]), when_set: types[2].to_owned() };
+                    }
+                }
+            },
+            ChatEvent::ChannelNames { ref channel, ref names } => {
+                let members = self.names.entry(channel.clone()).or_insert_with(HashMap::new);
+                for name in names.iter() {
+                    let nick = name.trim_left_matches(|c: char| MEMBER_PREFIXES.contains(c));
+                    let prefixes = &name[..name.len() - nick.len()];
+                    members.insert(nick.to_owned(), prefixes.to_owned());
+                }
+            },
+            ChatEvent::ChannelNamesEnd { ref channel } => {
+                match self.names.remove(channel) {
+                    Some(members) => self.channels.insert(channel.clone(), members),
+                    None => self.channels.remove(channel),
+                };
+            },
+            ChatEvent::ChannelMode { ref channel, ref modes, ref args, .. } => {
+                let members = match self.channels.get_mut(channel) {
+                    Some(members) => members,
+                    None => return,
+                };
+                let mut args = args.iter();
+                let mut adding = true;
+                for mode in modes.chars() {
+                    match (mode, mode_prefix(mode)) {
+                        ('+', _) => adding = true,
+                        ('-', _) => adding = false,
+                        (_, Some(prefix)) => if let Some(nick) = args.next() {
+                            let prefixes = members.entry(nick.clone()).or_insert_with(String::new);
+                            let mut kept: Vec<char> = prefixes.chars().filter(|&c| c != prefix).collect();
+                            if adding {
+                                kept.push(prefix);
+                                kept.sort_by_key(|&c| rank(c));
+                            }
+                            *prefixes = kept.into_iter().collect();
+                        },
+                        _ if self.modes.always.contains(mode) => { args.next(); },
+                        _ if adding && self.modes.when_set.contains(mode) => { args.next(); },
+                        _ => (),
+                    }
+                }
+            },
+            ChatEvent::JoinedChannel { ref channel, ref who } => {
+                self.channels.entry(channel.clone()).or_insert_with(HashMap::new).insert(who.clone(), String::new());
+            },
+            ChatEvent::PartedChannel { ref channel, ref who, .. } |
+            ChatEvent::Kicked { ref channel, ref who, .. } => {
+                if self.is_me(who) {
+                    self.channels.remove(channel);
+                } else {
+                    self.channels.get_mut(channel).map(|members| members.remove(who));
+                }
+                self.drop_empty();
+            },
+            ChatEvent::NickChanged { ref old, ref new } => {
+                if self.is_me(old) {
+                    self.nick = Some(new.clone());
+                }
+                for members in self.channels.values_mut() {
+                    if let Some(prefixes) = members.remove(old) {
+                        members.insert(new.clone(), prefixes);
+                    }
+                }
+            },
+            ChatEvent::Quit { ref who, .. } => {
+                for members in self.channels.values_mut() {
+                    members.remove(who);
+                }
+                self.drop_empty();
+            },
+            _ => (),
+        }
+    }
+
+    fn is_me(&self, nick: &str) -> bool {
+        self.nick.as_ref().map_or(false, |me| me == nick)
+    }
+
+    fn drop_empty(&mut self) {
+        let empty: Vec<String> = self.channels.iter().filter(|&(_, members)| members.is_empty()).map(|(channel, _)| channel.clone()).collect();
+        for channel in empty {
+            self.channels.remove(&channel);
+        }
+    }
+
+    pub fn channels(&self) -> Vec<String> {
+        let mut channels: Vec<String> = self.channels.keys().cloned().collect();
+        channels.sort();
+        channels
+    }
+
+    pub fn members(&self, channel: &str) -> Vec<(String, String)> {
+        let mut members: Vec<(String, String)> = self.channels.get(channel)
+            .map_or(Vec::new(), |members| members.iter().map(|(nick, prefixes)| (nick.clone(), prefixes.clone())).collect());
+        members.sort();
+        members
+    }
+
+    pub fn prefixes(&self, channel: &str, nick: &str) -> Option<&str> {
+        self.channels.get(channel).and_then(|members| members.get(nick)).map(|prefixes| prefixes.as_str())
+    }
+
+    pub fn is_member(&self, channel: &str, nick: &str) -> bool {
+        self.prefixes(channel, nick).is_some()
+    }
+
+    pub fn has_status(&self, channel: &str, nick: &str) -> bool {
+        self.prefixes(channel, nick).map_or(false, |prefixes| !prefixes.is_empty())
+    }
+
+    pub fn is_op(&self, channel: &str, nick: &str) -> bool {
+        self.prefixes(channel, nick).map_or(false, |prefixes| prefixes.chars().any(|c| rank(c) <= rank('@')))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use channel_state::*;
+    use irc::ChatEvent;
+
+    fn names(channel: &str, names: &[&str]) -> ChatEvent {
+        ChatEvent::ChannelNames { channel: channel.to_owned(), names: names.iter().map(|n| n.to_string()).collect() }
+    }
+
+    fn names_end(channel: &str) -> ChatEvent {
+        ChatEvent::ChannelNamesEnd { channel: channel.to_owned() }
+    }
+
+    fn mode(channel: &str, modes: &str, args: &[&str]) -> ChatEvent {
+        ChatEvent::ChannelMode { channel: channel.to_owned(), by: "op".to_owned(), modes: modes.to_owned(), args: args.iter().map(|a| a.to_string()).collect() }
+    }
+
+    #[test]
+    fn track_names_test() {
+        let mut state = ChannelState::new();
+        state.update(&names("#chan", &["@op", "+voiced"]));
+        assert_eq!(state.channels(), Vec::<String>::new());
+        state.update(&names("#chan", &["user", "~@owner"]));
+        state.update(&names_end("#chan"));
+        assert_eq!(state.channels(), vec!["#chan".to_owned()]);
+        assert_eq!(state.members("#chan"), vec![
+            ("op".to_owned(), "@".to_owned()),
+            ("owner".to_owned(), "~@".to_owned()),
+            ("user".to_owned(), "".to_owned()),
+            ("voiced".to_owned(), "+".to_owned())]);
+        assert!(state.is_op("#chan", "op") && state.is_op("#chan", "owner"));
+        assert!(!state.is_op("#chan", "voiced") && state.has_status("#chan", "voiced"));
+        assert!(state.is_member("#chan", "user") && !state.has_status("#chan", "user"));
+        assert!(!state.is_member("#chan", "stranger") && !state.is_member("#other", "user"));
+    }
+
+    #[test]
+    fn track_modes_test() {
+        let mut state = ChannelState::new();
+        state.update(&names("#chan", &["user1", "user2"]));
+        state.update(&names_end("#chan"));
+        state.update(&mode("#chan", "+vbo", &["user1", "*!*@spam", "user1"]));
+        state.update(&mode("#chan", "+l-v+k", &["10", "user1", "key"]));
+        state.update(&mode("#chan", "+o-o", &["user2", "user2"]));
+        assert_eq!(state.prefixes("#chan", "user1"), Some("@"));
+        assert_eq!(state.prefixes("#chan", "user2"), Some(""));
+        state.update(&mode("#chan", "+v", &["user1"]));
+        assert_eq!(state.prefixes("#chan", "user1"), Some("@+"));
+    }
+
+    #[test]
+    fn skip_arguments_of_server_specific_modes_test() {
+        let mut state = ChannelState::new();
+        state.update(&names("#chan", &["user1", "user2"]));
+        state.update(&names_end("#chan"));
+        state.update(&ChatEvent::ServerSupport { tokens: vec!["PREFIX=(ov)@+".to_owned(), "CHANMODES=beI,kfL,lj,psmnt".to_owned()] });
+        state.update(&mode("#chan", "+fvj-Lo", &["#overflow", "user1", "3:5", "#big", "user2"]));
+        assert_eq!(state.prefixes("#chan", "user1"), Some("+"));
+        assert_eq!(state.prefixes("#chan", "user2"), Some(""));
+        assert!(!state.is_member("#chan", "#overflow") && !state.is_member("#chan", "#big"));
+    }
+
+    #[test]
+    fn ignore_modes_in_untracked_channels_test() {
+        let mut state = ChannelState::new();
+        state.update(&mode("#elsewhere", "+o", &["user1"]));
+        assert_eq!(state.channels(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn track_joins_and_parts_test() {
+        let mut state = ChannelState::new();
+        state.update(&ChatEvent::JoinedChannel { channel: "#chan".to_owned(), who: "user1".to_owned() });
+        state.update(&ChatEvent::JoinedChannel { channel: "#chan".to_owned(), who: "user2".to_owned() });
+        state.update(&ChatEvent::PartedChannel { channel: "#chan".to_owned(), who: "user1".to_owned(), comment: None });
+        assert_eq!(state.members("#chan"), vec![("user2".to_owned(), "".to_owned())]);
+        state.update(&ChatEvent::Kicked { channel: "#chan".to_owned(), who: "user2".to_owned(), by: "op".to_owned(), reason: None });
+        assert_eq!(state.channels(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn track_nicks_and_quits_test() {
+        let mut state = ChannelState::new();
+        state.update(&names("#a", &["@user1", "user2"]));
+        state.update(&names_end("#a"));
+        state.update(&names("#b", &["user1", "+user2"]));
+        state.update(&names_end("#b"));
+        state.update(&names("#c", &["user2"]));
+        state.update(&names_end("#c"));
+        state.update(&ChatEvent::NickChanged { old: "user1".to_owned(), new: "renamed".to_owned() });
+        state.update(&ChatEvent::Quit { who: "user2".to_owned(), reason: None });
+        assert_eq!(state.members("#a"), vec![("renamed".to_owned(), "@".to_owned())]);
+        assert_eq!(state.members("#b"), vec![("renamed".to_owned(), "".to_owned())]);
+        assert_eq!(state.channels(), vec!["#a".to_owned(), "#b".to_owned()]);
+    }
+
+    #[test]
+    fn replace_members_from_fresh_names_test() {
+        let mut state = ChannelState::new();
+        state.update(&names("#chan", &["@op", "user"]));
+        state.update(&names_end("#chan"));
+        state.update(&names("#chan", &["op", "+user"]));
+        assert!(state.is_op("#chan", "op"));
+        state.update(&names_end("#chan"));
+        assert!(!state.is_op("#chan", "op"));
+        assert_eq!(state.prefixes("#chan", "user"), Some("+"));
+    }
+
+    #[test]
+    fn forget_channels_the_bot_leaves_test() {
+        let mut state = ChannelState::new();
+        state.update(&ChatEvent::Connected { nick: "bot".to_owned() });
+        state.update(&ChatEvent::NickChanged { old: "bot".to_owned(), new: "bot_".to_owned() });
+        for channel in vec!["#a", "#b"] {
+            state.update(&names(channel, &["bot_", "@op"]));
+            state.update(&names_end(channel));
+        }
+        state.update(&ChatEvent::PartedChannel { channel: "#a".to_owned(), who: "bot_".to_owned(), comment: None });
+        state.update(&ChatEvent::Kicked { channel: "#b".to_owned(), who: "bot_".to_owned(), by: "op".to_owned(), reason: None });
+        assert_eq!(state.channels(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn reset_on_reconnect_test() {
+        let mut state = ChannelState::new();
+        state.update(&ChatEvent::Connected { nick: "bot".to_owned() });
+        state.update(&names("#chan", &["bot", "@op"]));
+        state.update(&names_end("#chan"));
+        state.update(&ChatEvent::Disconnected);
+        assert!(!state.is_op("#chan", "op"));
+
+        state.update(&names("#chan", &["bot", "@op"]));
+        state.update(&ChatEvent::Connected { nick: "bot".to_owned() });
+        state.update(&names_end("#chan"));
+        assert_eq!(state.channels(), Vec::<String>::new());
+    }
+}
@@ -1,37 +1,96 @@
 use db;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::collections::Bound;
+use std::iter;
+use std::usize;
 
+/// Same sorted key order as `RocksDBKV`.
 pub struct HashMapKV {
-    inner: HashMap<String, String>
+    inner: BTreeMap<String, String>
 }
 
 impl HashMapKV {
     pub fn new() -> HashMapKV {
-        HashMapKV { inner: HashMap::new() }
+        HashMapKV { inner: BTreeMap::new() }
     }
+
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+}
+
+fn max_bound<F>(a: Option<String>, b: Option<String>, prefer_a: F) -> Option<String> where F: Fn(&String, &String) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(if prefer_a(&a, &b) { a } else { b }),
+        (a, b) => a.or(b),
+    }
+}
+
+// The least string greater than every string starting with `prefix`.
+fn successor(prefix: &str) -> Option<String> {
+    let mut chars: Vec<char> = prefix.chars().collect();
+    while let Some(last) = chars.pop() {
+        if let Some(next) = (last as u32 + 1..0x110000).filter_map(char::from_u32).next() {
+            chars.push(next);
+            return Some(chars.into_iter().collect())
+        }
+    }
+    None
 }
 
 impl db::KV<String, String> for HashMapKV {
-    fn put(&mut self, key: &String, value: &String) -> Result<(), String> {
+    fn put(&mut self, key: &String, value: &String) -> Result<(), db::Error> {
         let _ = self.inner.insert(key.clone(), value.clone());
         Ok(())
     }
 
-    fn get(&self, key: &String) -> Result<Option<String>, String> {
+    fn get(&self, key: &String) -> Result<Option<String>, db::Error> {
         Ok(self.inner.get(key).map(|v| v.clone()))
     }
 
-    fn get_prefix(&self, prefix: &String) -> Vec<(String, String)> {
-        self.inner.iter()
-           .filter(|&(k,_)| k.starts_with(prefix))
-           .map(|(k, v)| (k.clone(), v.clone()))
-           .collect::<Vec<(String, String)>>()
+    fn scan<'a>(&'a self, scan: db::Scan<String>) -> db::ScanIter<'a, String, String> {
+        let limit = scan.limit.unwrap_or(usize::MAX);
+        let lower = max_bound(scan.prefix.clone(), scan.start.clone(), |p, s| p > s);
+        let upper = max_bound(scan.prefix.as_ref().and_then(|p| successor(p)), scan.end.clone(), |p, e| p < e);
+        if let (Some(l), Some(u)) = (lower.as_ref(), upper.as_ref()) {
+            if l >= u {
+                return Box::new(iter::empty())
+            }
+        }
+        let range = self.inner.range::<String, _>((
+            lower.map_or(Bound::Unbounded, Bound::Included),
+            upper.map_or(Bound::Unbounded, Bound::Excluded)));
+        let iter: Box<Iterator<Item=(&'a String, &'a String)> + 'a> =
+            if scan.reverse { Box::new(range.rev()) } else { Box::new(range) };
+        Box::new(iter
+            .take(limit)
+            .map(|(k, v)| Ok((k.clone(), v.clone()))))
     }
 
-    fn remove(&mut self, key: &String) -> Result<(), String> {
+    fn remove(&mut self, key: &String) -> Result<(), db::Error> {
         self.inner.remove(key);
         Ok(())
     }
+
+    fn write(&mut self, batch: db::Batch<String, String>) -> Result<(), db::Error> {
+        for op in batch.into_ops() {
+            match op {
+                db::BatchOp::Put(k, v) => { self.inner.insert(k, v); },
+                db::BatchOp::Delete(k) => { self.inner.remove(&k); },
+            }
+        }
+        Ok(())
+    }
+
+    fn snapshot(&self) -> Result<Box<db::Snapshot>, db::Error> {
+        Ok(Box::new(HashMapKV { inner: self.inner.clone() }))
+    }
+}
+
+impl db::Snapshot for HashMapKV {
+    fn copy_to(&self, to: &mut db::KV<String, String>) -> Result<usize, db::Error> {
+        db::copy(self, to)
+    }
 }
 
 #[cfg(test)]
@@ -70,6 +129,56 @@ mod test {
         db::test::fetch_keys_by_prefix_test(db, prefix, rand_key, prefixed_key, rand_value)
     }
 
+    #[test]
+    fn write_batch_test() {
+        let db = HashMapKV::new();
+        db::test::write_batch_test(db, rand_key(), rand_key(), rand_key(), rand_value(), rand_value())
+    }
+
+    #[test]
+    fn write_batch_in_order_test() {
+        let db = HashMapKV::new();
+        db::test::write_batch_in_order_test(db, rand_key(), rand_key(), rand_value())
+    }
+
+    #[test]
+    fn fetch_keys_in_key_order_test() {
+        db::test::fetch_keys_in_key_order_test(HashMapKV::new())
+    }
+
+    #[test]
+    fn scan_range_test() {
+        db::test::scan_range_test(HashMapKV::new())
+    }
+
+    #[test]
+    fn scan_reverse_test() {
+        db::test::scan_reverse_test(HashMapKV::new())
+    }
+
+    #[test]
+    fn scan_with_limit_test() {
+        db::test::scan_with_limit_test(HashMapKV::new())
+    }
+
+    #[test]
+    fn fetch_tuple_keys_by_prefix_test() {
+        db::test::fetch_tuple_keys_by_prefix_test(HashMapKV::new())
+    }
+
+    #[test]
+    fn copy_snapshot_test() {
+        db::test::copy_snapshot_test(HashMapKV::new())
+    }
+
+    #[test]
+    fn successor_test() {
+        assert_eq!(successor("ab"), Some("ac".to_owned()));
+        assert_eq!(successor("a\u{10ffff}"), Some("b".to_owned()));
+        assert_eq!(successor("\u{d7ff}"), Some("\u{e000}".to_owned()));
+        assert_eq!(successor(""), None);
+    }
+
     extern crate rand;
 
     fn rand_key() -> String {
@@ -0,0 +1,112 @@
+use db;
+use db::typed::Key;
+
+const TERMINATOR: char = '\u{0}';
+const ESCAPE: char = '\u{1}';
+
+/// Encodes so that a tuple is a key prefix of every tuple it starts.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Tuple {
+    components: Vec<String>,
+}
+
+impl Tuple {
+    pub fn new() -> Tuple {
+        Tuple { components: Vec::new() }
+    }
+
+    pub fn push(mut self, component: &str) -> Tuple {
+        self.components.push(component.to_owned());
+        self
+    }
+
+    pub fn components(&self) -> &[String] {
+        &self.components
+    }
+
+    pub fn decode(key: &str) -> Result<Tuple, db::Error> {
+        let mut components = Vec::new();
+        let mut component = String::new();
+        let mut chars = key.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                TERMINATOR => components.push(component.split_off(0)),
+                ESCAPE => match chars.next() {
+                    Some('\u{1}') => component.push(TERMINATOR),
+                    Some('\u{2}') => component.push(ESCAPE),
+                    _ => return Err(db::Error::Encoding(format!("invalid escape in tuple key {:?}", key))),
+                },
+                c => component.push(c),
+            }
+        }
+        if !component.is_empty() {
+            return Err(db::Error::Encoding(format!("unterminated tuple key {:?}", key)))
+        }
+        Ok(Tuple { components: components })
+    }
+}
+
+impl Key for Tuple {
+    fn encode(&self) -> String {
+        let mut key = String::new();
+        for component in self.components.iter() {
+            for c in component.chars() {
+                match c {
+                    TERMINATOR => key.push_str("\u{1}\u{1}"),
+                    ESCAPE => key.push_str("\u{1}\u{2}"),
+                    c => key.push(c),
+                }
+            }
+            key.push(TERMINATOR);
+        }
+        key
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use db;
+    use db::key::*;
+    use db::typed::Key;
+
+    fn tuple(components: &[&str]) -> Tuple {
+        components.iter().fold(Tuple::new(), |t, c| t.push(c))
+    }
+
+    #[test]
+    fn encode_and_decode_test() {
+        for t in vec![tuple(&[]), tuple(&["line", "#chan", "#tag", "abcd1234"]), tuple(&["", "a\u{0}b", "\u{1}\u{2}", "ä-ö"])] {
+            assert_eq!(Tuple::decode(&t.encode()), Ok(t));
+        }
+    }
+
+    #[test]
+    fn components_do_not_run_together_test() {
+        let foo = tuple(&["line", "#chan", "#foo"]).encode();
+        let foo_bar = tuple(&["line", "#chan", "#foo-bar", "abcd1234"]).encode();
+        assert!(!foo_bar.starts_with(&foo));
+        assert!(tuple(&["line", "#chan", "#foo", "abcd1234"]).encode().starts_with(&foo));
+        assert!(tuple(&["a-b", "c"]).encode() != tuple(&["a", "b-c"]).encode());
+    }
+
+    #[test]
+    fn preserve_order_test() {
+        let mut tuples = vec![
+            tuple(&["a"]), tuple(&["a", ""]), tuple(&["a", "b"]), tuple(&["a\u{0}", "a"]),
+            tuple(&["a\u{1}"]), tuple(&["a-b"]), tuple(&["ab"]), tuple(&["b"]), tuple(&[""])];
+        tuples.sort();
+        let mut encoded = tuples.iter().map(|t| t.encode()).collect::<Vec<String>>();
+        encoded.sort();
+        assert_eq!(encoded.iter().map(|k| Tuple::decode(k).unwrap()).collect::<Vec<Tuple>>(), tuples);
+    }
+
+    #[test]
+    fn reject_malformed_keys_test() {
+        for key in vec!["a", "a\u{0}b", "a\u{1}\u{3}\u{0}", "a\u{1}"] {
+            match Tuple::decode(key) {
+                Err(db::Error::Encoding(_)) => (),
+                other => panic!("{:?} decoded to {:?}", key, other),
+            }
+        }
+    }
+}
@@ -0,0 +1,414 @@
+extern crate serde_json;
+
+use db;
+use db::KV;
+use db::hashmap_kv::HashMapKV;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::str;
+
+const COMPACT_MIN_RECORDS: usize = 1024;
+const COMPACT_BATCH_SIZE: usize = 256;
+
+pub struct LogKV {
+    path: PathBuf,
+    log: File,
+    size: u64,
+    records: usize,
+    compact_after: usize,
+    index: HashMapKV,
+}
+
+fn io_error(err: io::Error) -> db::Error {
+    db::Error::Io(err.to_string())
+}
+
+fn encode_batch(batch: &db::Batch<String, String>) -> Result<String, db::Error> {
+    let ops: Vec<Vec<&str>> = batch.ops().iter().map(|op| match *op {
+        db::BatchOp::Put(ref k, ref v) => vec!["put", k.as_str(), v.as_str()],
+        db::BatchOp::Delete(ref k) => vec!["delete", k.as_str()],
+    }).collect();
+    let mut line = try!(serde_json::to_string(&ops).map_err(|e| db::Error::Encoding(e.to_string())));
+    line.push('\n');
+    Ok(line)
+}
+
+fn decode_batch(line: &str) -> Option<db::Batch<String, String>> {
+    let ops: Vec<Vec<String>> = match serde_json::from_str(line) {
+        Ok(ops) => ops,
+        Err(_) => return None,
+    };
+    let mut batch = db::Batch::new();
+    for op in ops {
+        if op.len() == 3 && op[0] == "put" {
+            batch.put(op[1].clone(), op[2].clone())
+        } else if op.len() == 2 && op[0] == "delete" {
+            batch.delete(op[1].clone())
+        } else {
+            return None
+        }
+    }
+    Some(batch)
+}
+
+// Applies the batches in `contents` to `index`, returning how many records
+// they hold and where the last one ends; only an unterminated tail is torn.
+fn replay(contents: &[u8], index: &mut HashMapKV, path: &Path) -> Result<(usize, usize), db::Error> {
+    let mut records = 0;
+    let mut offset = 0;
+    while offset < contents.len() {
+        let end = contents[offset..].iter().position(|&b| b == b'\n').map(|i| offset + i);
+        let batch = end.and_then(|end| str::from_utf8(&contents[offset..end]).ok()).and_then(decode_batch);
+        match (end, batch) {
+            (Some(end), Some(batch)) => {
+                records += batch.len();
+                try!(index.write(batch));
+                offset = end + 1
+            },
+            (Some(_), None) =>
+                return Err(db::Error::Corruption(format!("unreadable batch at byte {} of {}", offset, path.display()))),
+            (None, _) => break,
+        }
+    }
+    Ok((records, offset))
+}
+
+// The log as it was when the snapshot was taken: compaction replaces the
+// file rather than rewriting it, so the open handle keeps seeing it.
+struct LogSnapshot {
+    path: PathBuf,
+    log: File,
+    size: u64,
+}
+
+impl db::Snapshot for LogSnapshot {
+    fn copy_to(&self, to: &mut db::KV<String, String>) -> Result<usize, db::Error> {
+        let mut contents = Vec::new();
+        try!((&self.log).take(self.size).read_to_end(&mut contents).map_err(io_error));
+        let mut index = HashMapKV::new();
+        try!(replay(&contents, &mut index, &self.path));
+        db::copy(&index, to)
+    }
+}
+
+impl LogKV {
+    pub fn open(path: &Path) -> Result<LogKV, db::Error> {
+        let mut log = try!(OpenOptions::new().read(true).append(true).create(true).open(path).map_err(io_error));
+        let mut contents = Vec::new();
+        try!(log.read_to_end(&mut contents).map_err(io_error));
+
+        let mut index = HashMapKV::new();
+        let (records, offset) = try!(replay(&contents, &mut index, path));
+        if offset < contents.len() {
+            try!(log.set_len(offset as u64).map_err(io_error));
+            try!(log.sync_all().map_err(io_error));
+        }
+        Ok(LogKV { path: path.to_path_buf(), log: log, size: offset as u64, records: records, compact_after: COMPACT_MIN_RECORDS, index: index })
+    }
+
+    pub fn compact(&mut self) -> Result<(), db::Error> {
+        let tmp = PathBuf::from(format!("{}.compact", self.path.display()));
+        let mut size = 0;
+        {
+            let mut out = try!(File::create(&tmp).map_err(io_error));
+            let mut batch = db::Batch::new();
+            for pair in self.index.scan(db::Scan::all()) {
+                let (k, v) = try!(pair);
+                batch.put(k, v);
+                if batch.len() == COMPACT_BATCH_SIZE {
+                    let line = try!(encode_batch(&batch));
+                    try!(out.write_all(line.as_bytes()).map_err(io_error));
+                    size += line.len();
+                    batch = db::Batch::new();
+                }
+            }
+            if !batch.is_empty() {
+                let line = try!(encode_batch(&batch));
+                try!(out.write_all(line.as_bytes()).map_err(io_error));
+                size += line.len();
+            }
+            try!(out.sync_all().map_err(io_error));
+        }
+        let log = try!(OpenOptions::new().read(true).append(true).open(&tmp).map_err(io_error));
+        let dir = match self.path.parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new("."),
+        };
+        let dir = try!(File::open(dir).map_err(io_error));
+        try!(fs::rename(&tmp, &self.path).map_err(io_error));
+        self.log = log;
+        self.size = size as u64;
+        self.records = self.index.len();
+        self.compact_after = COMPACT_MIN_RECORDS;
+        // Once renamed the compacted file is the log, so the state above
+        // follows it even if the rename is not yet durable.
+        dir.sync_all().map_err(io_error)
+    }
+
+    fn append(&mut self, batch: &db::Batch<String, String>) -> Result<(), db::Error> {
+        let line = try!(encode_batch(batch));
+        let written = self.log.write_all(line.as_bytes()).and_then(|_| self.log.sync_data());
+        if let Err(err) = written {
+            let _ = self.log.set_len(self.size);
+            return Err(io_error(err))
+        }
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+impl db::KV<String, String> for LogKV {
+    fn put(&mut self, key: &String, value: &String) -> Result<(), db::Error> {
+        let mut batch = db::Batch::new();
+        batch.put(key.clone(), value.clone());
+        self.write(batch)
+    }
+
+    fn get(&self, key: &String) -> Result<Option<String>, db::Error> {
+        self.index.get(key)
+    }
+
+    fn scan<'a>(&'a self, scan: db::Scan<String>) -> db::ScanIter<'a, String, String> {
+        self.index.scan(scan)
+    }
+
+    fn remove(&mut self, key: &String) -> Result<(), db::Error> {
+        let mut batch = db::Batch::new();
+        batch.delete(key.clone());
+        self.write(batch)
+    }
+
+    fn snapshot(&self) -> Result<Box<db::Snapshot>, db::Error> {
+        let log = try!(File::open(&self.path).map_err(io_error));
+        Ok(Box::new(LogSnapshot { path: self.path.clone(), log: log, size: self.size }))
+    }
+
+    fn write(&mut self, batch: db::Batch<String, String>) -> Result<(), db::Error> {
+        if batch.is_empty() {
+            return Ok(())
+        }
+        try!(self.append(&batch));
+        self.records += batch.len();
+        try!(self.index.write(batch));
+        if self.records > self.compact_after && self.records > 2 * self.index.len() {
+            if let Err(err) = self.compact() {
+                println!("Unable to compact {}: {}", self.path.display(), err);
+                self.compact_after = 2 * self.records
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use db;
+    use db::KV;
+    use db::log_kv::*;
+    use std::fs;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    extern crate tempdir;
+    use self::tempdir::TempDir;
+
+    #[test]
+    fn get_nonexistent_key_test() {
+        let (_dir, db) = new_temp_db();
+        db::test::get_nonexistent_key_test(db, rand_key())
+    }
+
+    #[test]
+    fn put_and_get_key_test() {
+        let (_dir, db) = new_temp_db();
+        db::test::put_and_get_key_test(db, rand_key(), rand_value())
+    }
+
+    #[test]
+    fn overwrite_key_test() {
+        let (_dir, db) = new_temp_db();
+        db::test::overwrite_key_test(db, rand_key(), rand_value(), rand_value())
+    }
+
+    #[test]
+    fn remove_key_test() {
+        let (_dir, db) = new_temp_db();
+        db::test::remove_key_test(db, rand_key(), rand_value())
+    }
+
+    #[test]
+    fn fetch_keys_by_prefix_test() {
+        let (_dir, db) = new_temp_db();
+        let prefix = "prefix".to_owned();
+        db::test::fetch_keys_by_prefix_test(db, prefix, rand_key, prefixed_key, rand_value)
+    }
+
+    #[test]
+    fn write_batch_test() {
+        let (_dir, db) = new_temp_db();
+        db::test::write_batch_test(db, rand_key(), rand_key(), rand_key(), rand_value(), rand_value())
+    }
+
+    #[test]
+    fn write_batch_in_order_test() {
+        let (_dir, db) = new_temp_db();
+        db::test::write_batch_in_order_test(db, rand_key(), rand_key(), rand_value())
+    }
+
+    #[test]
+    fn fetch_keys_in_key_order_test() {
+        let (_dir, db) = new_temp_db();
+        db::test::fetch_keys_in_key_order_test(db)
+    }
+
+    #[test]
+    fn scan_range_test() {
+        let (_dir, db) = new_temp_db();
+        db::test::scan_range_test(db)
+    }
+
+    #[test]
+    fn scan_reverse_test() {
+        let (_dir, db) = new_temp_db();
+        db::test::scan_reverse_test(db)
+    }
+
+    #[test]
+    fn scan_with_limit_test() {
+        let (_dir, db) = new_temp_db();
+        db::test::scan_with_limit_test(db)
+    }
+
+    #[test]
+    fn fetch_tuple_keys_by_prefix_test() {
+        let (_dir, db) = new_temp_db();
+        db::test::fetch_tuple_keys_by_prefix_test(db)
+    }
+
+    #[test]
+    fn copy_snapshot_test() {
+        let (_dir, db) = new_temp_db();
+        db::test::copy_snapshot_test(db)
+    }
+
+    #[test]
+    fn leave_later_writes_out_of_snapshot_test() {
+        let (_dir, mut db) = new_temp_db();
+        db.put(&"a".to_owned(), &"1".to_owned()).unwrap();
+        let snapshot = db.snapshot().unwrap();
+        db.put(&"b".to_owned(), &"2".to_owned()).unwrap();
+        db.compact().unwrap();
+        db.put(&"c".to_owned(), &"3".to_owned()).unwrap();
+
+        let mut copy = db::hashmap_kv::HashMapKV::new();
+        assert_eq!(snapshot.copy_to(&mut copy), Ok(1));
+        assert_eq!(copy.get(&"a".to_owned()), Ok(Some("1".to_owned())));
+    }
+
+    #[test]
+    fn reopen_test() {
+        let dir = TempDir::new("log_kv_test").unwrap();
+        let path = dir.path().join("db.log");
+        {
+            let mut db = LogKV::open(&path).unwrap();
+            db.put(&"a".to_owned(), &"1".to_owned()).unwrap();
+            db.put(&"b".to_owned(), &"2".to_owned()).unwrap();
+            db.remove(&"a".to_owned()).unwrap();
+        }
+        let db = LogKV::open(&path).unwrap();
+        assert_eq!(db.get(&"a".to_owned()), Ok(None));
+        assert_eq!(db.get(&"b".to_owned()), Ok(Some("2".to_owned())));
+    }
+
+    #[test]
+    fn truncate_torn_batch_test() {
+        let dir = TempDir::new("log_kv_test").unwrap();
+        let path = dir.path().join("db.log");
+        LogKV::open(&path).unwrap().put(&"a".to_owned(), &"1".to_owned()).unwrap();
+        let size = fs::metadata(&path).unwrap().len();
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(b"[[\"put\",\"b\",").unwrap();
+
+        let mut db = LogKV::open(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), size);
+        assert_eq!(db.get(&"a".to_owned()), Ok(Some("1".to_owned())));
+        assert_eq!(db.get(&"b".to_owned()), Ok(None));
+
+        db.put(&"c".to_owned(), &"3".to_owned()).unwrap();
+        let db = LogKV::open(&path).unwrap();
+        assert_eq!(db.get(&"c".to_owned()), Ok(Some("3".to_owned())));
+    }
+
+    #[test]
+    fn reject_corrupt_log_test() {
+        let dir = TempDir::new("log_kv_test").unwrap();
+        let path = dir.path().join("db.log");
+        fs::File::create(&path).unwrap().write_all(b"not a batch\n[[\"put\",\"a\",\"1\"]]\n").unwrap();
+        match LogKV::open(&path) {
+            Err(db::Error::Corruption(_)) => (),
+            _ => panic!(),
+        }
+
+        fs::File::create(&path).unwrap().write_all(b"[[\"put\",\"a\",\"1\"]]\nnot a batch\n").unwrap();
+        match LogKV::open(&path) {
+            Err(db::Error::Corruption(_)) => (),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn compact_test() {
+        let dir = TempDir::new("log_kv_test").unwrap();
+        let path = dir.path().join("db.log");
+        let mut db = LogKV::open(&path).unwrap();
+        for i in 0..2000 {
+            db.put(&format!("key{}", i % 10), &format!("value{}", i)).unwrap();
+        }
+        assert!(fs::metadata(&path).unwrap().len() < 40 * 1024);
+
+        let db = LogKV::open(&path).unwrap();
+        assert_eq!(db.scan(db::Scan::all()).count(), 10);
+        assert_eq!(db.get(&"key9".to_owned()), Ok(Some("value1999".to_owned())));
+    }
+
+    #[test]
+    fn keep_writing_when_compaction_fails_test() {
+        let dir = TempDir::new("log_kv_test").unwrap();
+        let path = dir.path().join("db.log");
+        fs::create_dir(dir.path().join("db.log.compact")).unwrap();
+        let mut db = LogKV::open(&path).unwrap();
+        for i in 0..2000 {
+            db.put(&format!("key{}", i % 10), &format!("value{}", i)).unwrap();
+        }
+        assert_eq!(db.compact_after, 2 * (COMPACT_MIN_RECORDS + 1));
+
+        let db = LogKV::open(&path).unwrap();
+        assert_eq!(db.get(&"key9".to_owned()), Ok(Some("value1999".to_owned())));
+    }
+
+    fn new_temp_db() -> (TempDir, LogKV) {
+        let dir = TempDir::new("log_kv_test").unwrap();
+        let db = LogKV::open(&dir.path().join("db.log")).unwrap();
+        (dir, db)
+    }
+
+    extern crate rand;
+
+    fn rand_key() -> String {
+        use self::rand::Rng;
+        let salt: String = rand::thread_rng().gen_ascii_chars().take(5).collect();
+        format!("key{}", salt)
+    }
+
+    fn rand_value() -> String {
+        use self::rand::Rng;
+        let salt: String = rand::thread_rng().gen_ascii_chars().take(5).collect();
+        format!("value{}", salt)
+    }
+
+    fn prefixed_key() -> String {
+        format!("prefix-{}", rand_key())
+    }
+}
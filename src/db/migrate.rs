@@ -0,0 +1,121 @@
+use db;
+use db::key::Tuple;
+use db::typed::{Key, Store};
+use db::hashmap_kv::HashMapKV;
+
+pub struct Migration<KV> {
+    pub version: u32,
+    pub name: &'static str,
+    pub run: fn(&mut Store<KV>) -> Result<usize, db::Error>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Applied {
+    pub version: u32,
+    pub name: &'static str,
+    pub records: usize,
+}
+
+pub fn version_key() -> String {
+    Tuple::new().push("meta").push("schema-version").encode()
+}
+
+pub fn schema_version<KV>(store: &Store<KV>) -> Result<u32, db::Error> where KV: db::KV<String, String> {
+    store.get::<u32>(&version_key()).map(|version| version.unwrap_or(0))
+}
+
+/// Steps must be ordered by version and idempotent: one interrupted before
+/// its version is recorded runs again.
+pub fn run<KV>(store: &mut Store<KV>, migrations: &[Migration<KV>]) -> Result<Vec<Applied>, db::Error> where KV: db::KV<String, String> {
+    let current = try!(schema_version(store));
+    let latest = migrations.last().map_or(0, |m| m.version);
+    if current > latest {
+        return Err(db::Error::Corruption(format!("schema version {} is newer than the supported version {}", current, latest)))
+    }
+
+    let mut applied = Vec::new();
+    for migration in migrations.iter().filter(|m| m.version > current) {
+        let records = try!((migration.run)(store));
+        try!(store.put(&version_key(), &migration.version));
+        applied.push(Applied { version: migration.version, name: migration.name, records: records });
+    }
+    Ok(applied)
+}
+
+pub fn dry_run<KV>(store: &Store<KV>, migrations: &[Migration<HashMapKV>]) -> Result<Vec<Applied>, db::Error> where KV: db::KV<String, String> {
+    let mut copy = Store::new(HashMapKV::new());
+    try!(db::copy(store.kv(), copy.kv_mut()));
+    run(&mut copy, migrations)
+}
+
+#[cfg(test)]
+mod test {
+    use db;
+    use db::KV;
+    use db::migrate::*;
+    use db::typed::Store;
+    use db::hashmap_kv::HashMapKV;
+
+    fn add_greeting(store: &mut Store<HashMapKV>) -> Result<usize, db::Error> {
+        try!(store.put(&"greeting", &"hello".to_owned()));
+        Ok(1)
+    }
+
+    fn shout_greeting(store: &mut Store<HashMapKV>) -> Result<usize, db::Error> {
+        match try!(store.get::<String>(&"greeting")) {
+            Some(greeting) => {
+                try!(store.put(&"greeting", &greeting.to_uppercase()));
+                Ok(1)
+            },
+            None => Ok(0),
+        }
+    }
+
+    fn migrations() -> Vec<Migration<HashMapKV>> {
+        vec![
+            Migration { version: 1, name: "greeting", run: add_greeting },
+            Migration { version: 2, name: "shout", run: shout_greeting },
+        ]
+    }
+
+    #[test]
+    fn run_migrations_in_order_test() {
+        let mut store = Store::new(HashMapKV::new());
+        assert_eq!(schema_version(&store), Ok(0));
+        assert_eq!(run(&mut store, &migrations()), Ok(vec![
+            Applied { version: 1, name: "greeting", records: 1 },
+            Applied { version: 2, name: "shout", records: 1 }]));
+        assert_eq!(schema_version(&store), Ok(2));
+        assert_eq!(store.get::<String>(&"greeting"), Ok(Some("HELLO".to_owned())));
+        assert_eq!(run(&mut store, &migrations()), Ok(vec![]));
+    }
+
+    #[test]
+    fn skip_applied_migrations_test() {
+        let mut store = Store::new(HashMapKV::new());
+        store.put(&version_key(), &1).unwrap();
+        assert_eq!(run(&mut store, &migrations()), Ok(vec![Applied { version: 2, name: "shout", records: 0 }]));
+        assert_eq!(store.get::<String>(&"greeting"), Ok(None));
+    }
+
+    #[test]
+    fn refuse_newer_schema_test() {
+        let mut store = Store::new(HashMapKV::new());
+        store.put(&version_key(), &3).unwrap();
+        match run(&mut store, &migrations()) {
+            Err(db::Error::Corruption(_)) => (),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn dry_run_leaves_store_untouched_test() {
+        let mut store = Store::new(HashMapKV::new());
+        store.put(&"greeting", &"hi".to_owned()).unwrap();
+        assert_eq!(dry_run(&store, &migrations()), Ok(vec![
+            Applied { version: 1, name: "greeting", records: 1 },
+            Applied { version: 2, name: "shout", records: 1 }]));
+        assert_eq!(schema_version(&store), Ok(0));
+        assert_eq!(store.kv().get(&"greeting".to_owned()), Ok(Some("\"hi\"".to_owned())));
+    }
+}
@@ -1,12 +1,166 @@
+use std::error;
+use std::fmt;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    Io(String),
+    Corruption(String),
+    Encoding(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref msg) => write!(f, "storage I/O error: {}", msg),
+            Error::Corruption(ref msg) => write!(f, "storage corruption: {}", msg),
+            Error::Encoding(ref msg) => write!(f, "storage encoding error: {}", msg),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Io(_) => "storage I/O error",
+            Error::Corruption(_) => "storage corruption",
+            Error::Encoding(_) => "storage encoding error",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum BatchOp<K, V> {
+    Put(K, V),
+    Delete(K),
+}
+
+/// Applied atomically and in order by `KV::write`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Batch<K, V> {
+    ops: Vec<BatchOp<K, V>>,
+}
+
+impl <K, V> Batch<K, V> {
+    pub fn new() -> Batch<K, V> {
+        Batch { ops: Vec::new() }
+    }
+
+    pub fn put(&mut self, key: K, value: V) {
+        self.ops.push(BatchOp::Put(key, value))
+    }
+
+    pub fn delete(&mut self, key: K) {
+        self.ops.push(BatchOp::Delete(key))
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn ops(&self) -> &[BatchOp<K, V>] {
+        &self.ops
+    }
+
+    pub fn into_ops(self) -> Vec<BatchOp<K, V>> {
+        self.ops
+    }
+}
+
+/// `start` is inclusive, `end` exclusive.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Scan<K> {
+    pub prefix: Option<K>,
+    pub start: Option<K>,
+    pub end: Option<K>,
+    pub reverse: bool,
+    pub limit: Option<usize>,
+}
+
+impl <K> Scan<K> {
+    pub fn all() -> Scan<K> {
+        Scan { prefix: None, start: None, end: None, reverse: false, limit: None }
+    }
+
+    pub fn prefix(prefix: K) -> Scan<K> {
+        Scan { prefix: Some(prefix), .. Scan::all() }
+    }
+
+    pub fn start(self, start: K) -> Scan<K> {
+        Scan { start: Some(start), .. self }
+    }
+
+    pub fn end(self, end: K) -> Scan<K> {
+        Scan { end: Some(end), .. self }
+    }
+
+    pub fn reverse(self) -> Scan<K> {
+        Scan { reverse: true, .. self }
+    }
+
+    pub fn limit(self, limit: usize) -> Scan<K> {
+        Scan { limit: Some(limit), .. self }
+    }
+}
+
+impl Scan<String> {
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.prefix.as_ref().map_or(true, |p| key.starts_with(p.as_bytes())) &&
+            self.start.as_ref().map_or(true, |s| key >= s.as_bytes()) &&
+            self.end.as_ref().map_or(true, |e| key < e.as_bytes())
+    }
+}
+
+pub type ScanIter<'a, K, V> = Box<Iterator<Item=Result<(K, V), Error>> + 'a>;
+
 pub trait KV<K, V> {
-    fn put(&mut self, key: &K, value: &V) -> Result<(), String>;
-    fn get(&self, key: &K) -> Result<Option<V>, String>;
-    fn get_prefix(&self, key: &K) -> Vec<(K, V)>;
-    fn remove(&mut self, key: &K) -> Result<(), String>;
+    fn put(&mut self, key: &K, value: &V) -> Result<(), Error>;
+    fn get(&self, key: &K) -> Result<Option<V>, Error>;
+    fn scan<'a>(&'a self, scan: Scan<K>) -> ScanIter<'a, K, V>;
+    fn remove(&mut self, key: &K) -> Result<(), Error>;
+    fn write(&mut self, batch: Batch<K, V>) -> Result<(), Error>;
+
+    fn get_prefix(&self, prefix: &K) -> Result<Vec<(K, V)>, Error> where K: Clone {
+        self.scan(Scan::prefix(prefix.clone())).collect()
+    }
+
+    fn snapshot(&self) -> Result<Box<Snapshot>, Error> {
+        Err(Error::Io("this store does not support snapshots".to_owned()))
+    }
+}
+
+/// A point-in-time view of a store, readable from another thread.
+pub trait Snapshot: Send {
+    fn copy_to(&self, to: &mut KV<String, String>) -> Result<usize, Error>;
+}
+
+pub fn copy<A: ?Sized, B: ?Sized>(from: &A, to: &mut B) -> Result<usize, Error> where A: KV<String, String>, B: KV<String, String> {
+    copy_pairs(from.scan(Scan::all()), to)
+}
+
+pub fn copy_pairs<I, B: ?Sized>(pairs: I, to: &mut B) -> Result<usize, Error> where I: Iterator<Item=Result<(String, String), Error>>, B: KV<String, String> {
+    let mut batch = Batch::new();
+    for pair in pairs {
+        let (key, value) = try!(pair);
+        batch.put(key, value);
+    }
+    let copied = batch.len();
+    try!(to.write(batch));
+    Ok(copied)
 }
 
+#[cfg(feature = "rocksdb")]
 pub mod rocksdb_kv;
 pub mod hashmap_kv;
+pub mod log_kv;
+#[cfg(feature = "sqlite")]
+pub mod sqlite_kv;
+pub mod typed;
+pub mod key;
+pub mod migrate;
 
 #[cfg(test)]
 pub mod test;
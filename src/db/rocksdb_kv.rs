@@ -2,46 +2,138 @@ extern crate rocksdb;
 
 use db;
 use std::path::Path;
+use std::sync::Arc;
+use std::usize;
 
 pub struct RocksDBKV {
-    rocks_db: rocksdb::DB,
+    rocks_db: Arc<rocksdb::DB>,
+}
+
+fn decode_pair((k, v): (Box<[u8]>, Box<[u8]>)) -> Result<(String, String), db::Error> {
+    let key = try!(String::from_utf8(k.into_vec())
+        .map_err(|e| db::Error::Encoding(format!("key is not valid UTF-8: {}", e))));
+    let value = try!(String::from_utf8(v.into_vec())
+        .map_err(|e| db::Error::Encoding(format!("value of key {} is not valid UTF-8: {}", key, e))));
+    Ok((key, value))
+}
+
+// The 0.4 bindings have no checkpoint API, so this reads a RocksDB snapshot
+// taken when `copy_to` starts on the reading thread.
+struct RocksDBSnapshot {
+    rocks_db: Arc<rocksdb::DB>,
+}
+
+impl db::Snapshot for RocksDBSnapshot {
+    fn copy_to(&self, to: &mut db::KV<String, String>) -> Result<usize, db::Error> {
+        let snapshot = self.rocks_db.snapshot();
+        let pairs = snapshot.iterator(rocksdb::IteratorMode::Start).map(decode_pair);
+        db::copy_pairs(pairs, to)
+    }
 }
 
 impl RocksDBKV {
-    pub fn new(path: &Path) -> RocksDBKV {
-        let path_str = path.to_str().unwrap();
-        let db = rocksdb::DB::open_default(path_str).unwrap();
-        RocksDBKV { rocks_db: db }
+    pub fn new(path: &Path) -> Result<RocksDBKV, db::Error> {
+        let path_str = try!(path.to_str()
+            .ok_or_else(|| db::Error::Encoding(format!("path {} is not valid UTF-8", path.display()))));
+        let db = try!(rocksdb::DB::open_default(path_str).map_err(db::Error::Io));
+        Ok(RocksDBKV { rocks_db: Arc::new(db) })
     }
 
-    fn _put(&self, key: &String, value: &String) -> Result<(), String> {
+    fn _put(&self, key: &String, value: &String) -> Result<(), db::Error> {
         use self::rocksdb::Writable;
-        self.rocks_db.put(key.as_bytes(), value.as_bytes())
+        self.rocks_db.put(key.as_bytes(), value.as_bytes()).map_err(db::Error::Io)
     }
 
-    fn _get(&self, key: &String) -> Result<Option<String>, String> {
-        self.rocks_db.get(key.as_bytes()).map(|ob| ob.and_then(|b| b.to_utf8().map(|x| x.to_owned())))
+    fn _get(&self, key: &String) -> Result<Option<String>, db::Error> {
+        match self.rocks_db.get(key.as_bytes()) {
+            Ok(Some(bytes)) => match bytes.to_utf8() {
+                Some(value) => Ok(Some(value.to_owned())),
+                None => Err(db::Error::Encoding(format!("value of key {} is not valid UTF-8", key))),
+            },
+            Ok(None) => Ok(None),
+            Err(e) => Err(db::Error::Io(e)),
+        }
     }
 
-    fn _get_prefix(&self, prefix: &String) -> Vec<(String, String)> {
+    fn _scan<'a>(&'a self, scan: db::Scan<String>) -> db::ScanIter<'a, String, String> {
         use self::rocksdb::{Direction, IteratorMode};
-        self.rocks_db.iterator(IteratorMode::From(prefix.as_bytes(), Direction::Forward))
-            .map(|p| (String::from_utf8(p.0.into_vec()).unwrap(), String::from_utf8(p.1.into_vec()).unwrap()))
-            .take_while(|p| p.0.starts_with(prefix))
-            .collect::<Vec<(String, String)>>()
+
+        let iter = if scan.reverse {
+            match upper_bound(&scan) {
+                Some(ref upper) if self.rocks_db.iterator(IteratorMode::From(upper, Direction::Forward)).next().is_some() =>
+                    self.rocks_db.iterator(IteratorMode::From(upper, Direction::Reverse)),
+                _ => self.rocks_db.iterator(IteratorMode::End),
+            }
+        } else {
+            match lower_bound(&scan) {
+                Some(ref lower) => self.rocks_db.iterator(IteratorMode::From(lower, Direction::Forward)),
+                None => self.rocks_db.iterator(IteratorMode::Start),
+            }
+        };
+
+        let limit = scan.limit.unwrap_or(usize::MAX);
+        let upper = if scan.reverse { upper_bound(&scan) } else { None };
+        Box::new(iter
+            .skip_while(move |&(ref k, _)| upper.as_ref().map_or(false, |u| &**k >= &u[..]))
+            .take_while(move |&(ref k, _)| scan.contains(k))
+            .take(limit)
+            .map(decode_pair))
     }
 
-    fn _remove(&self, key: &String) -> Result<(), String> {
+    fn _remove(&self, key: &String) -> Result<(), db::Error> {
         use self::rocksdb::Writable;
-        self.rocks_db.delete(key.as_bytes())
+        self.rocks_db.delete(key.as_bytes()).map_err(db::Error::Io)
+    }
+
+    fn _write(&self, batch: db::Batch<String, String>) -> Result<(), db::Error> {
+        use self::rocksdb::Writable;
+        let write_batch = rocksdb::WriteBatch::new();
+        for op in batch.ops() {
+            try!(match *op {
+                db::BatchOp::Put(ref k, ref v) => write_batch.put(k.as_bytes(), v.as_bytes()),
+                db::BatchOp::Delete(ref k) => write_batch.delete(k.as_bytes()),
+            }.map_err(db::Error::Io));
+        }
+        self.rocks_db.write(write_batch).map_err(db::Error::Io)
+    }
+}
+
+fn lower_bound(scan: &db::Scan<String>) -> Option<Vec<u8>> {
+    let prefix = scan.prefix.as_ref().map(|p| p.as_bytes().to_vec());
+    let start = scan.start.as_ref().map(|s| s.as_bytes().to_vec());
+    match (prefix, start) {
+        (Some(p), Some(s)) => Some(if p > s { p } else { s }),
+        (p, s) => p.or(s),
+    }
+}
+
+fn upper_bound(scan: &db::Scan<String>) -> Option<Vec<u8>> {
+    let prefix = scan.prefix.as_ref().and_then(|p| successor(p.as_bytes()));
+    let end = scan.end.as_ref().map(|e| e.as_bytes().to_vec());
+    match (prefix, end) {
+        (Some(p), Some(e)) => Some(if p < e { p } else { e }),
+        (p, e) => p.or(e),
+    }
+}
+
+fn successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut bytes = prefix.to_vec();
+    while let Some(last) = bytes.pop() {
+        if last < 0xff {
+            bytes.push(last + 1);
+            return Some(bytes)
+        }
     }
+    None
 }
 
 impl db::KV<String, String> for RocksDBKV {
-    fn put(&mut self, key: &String, value: &String) -> Result<(), String> { self._put(key, value) }
-    fn get(&self, key: &String) -> Result<Option<String>, String> { self._get(key) }
-    fn get_prefix(&self, prefix: &String) -> Vec<(String, String)> { self._get_prefix(prefix) }
-    fn remove(&mut self, key: &String) -> Result<(), String> { self._remove(key) }
+    fn put(&mut self, key: &String, value: &String) -> Result<(), db::Error> { self._put(key, value) }
+    fn get(&self, key: &String) -> Result<Option<String>, db::Error> { self._get(key) }
+    fn scan<'a>(&'a self, scan: db::Scan<String>) -> db::ScanIter<'a, String, String> { self._scan(scan) }
+    fn remove(&mut self, key: &String) -> Result<(), db::Error> { self._remove(key) }
+    fn write(&mut self, batch: db::Batch<String, String>) -> Result<(), db::Error> { self._write(batch) }
+    fn snapshot(&self) -> Result<Box<db::Snapshot>, db::Error> { Ok(Box::new(RocksDBSnapshot { rocks_db: self.rocks_db.clone() })) }
 }
 
 
@@ -81,11 +173,83 @@ mod test {
         db::test::fetch_keys_by_prefix_test(db, prefix, rand_key, prefixed_key, rand_value)
     }
 
+    #[test]
+    fn write_batch_test() {
+        let db = new_temp_db();
+        db::test::write_batch_test(db, rand_key(), rand_key(), rand_key(), rand_value(), rand_value())
+    }
+
+    #[test]
+    fn write_batch_in_order_test() {
+        let db = new_temp_db();
+        db::test::write_batch_in_order_test(db, rand_key(), rand_key(), rand_value())
+    }
+
+    #[test]
+    fn fetch_keys_in_key_order_test() {
+        db::test::fetch_keys_in_key_order_test(new_temp_db())
+    }
+
+    #[test]
+    fn scan_range_test() {
+        db::test::scan_range_test(new_temp_db())
+    }
+
+    #[test]
+    fn scan_reverse_test() {
+        db::test::scan_reverse_test(new_temp_db())
+    }
+
+    #[test]
+    fn scan_with_limit_test() {
+        db::test::scan_with_limit_test(new_temp_db())
+    }
+
+    #[test]
+    fn fetch_tuple_keys_by_prefix_test() {
+        db::test::fetch_tuple_keys_by_prefix_test(new_temp_db())
+    }
+
+    #[test]
+    fn copy_snapshot_test() {
+        db::test::copy_snapshot_test(new_temp_db())
+    }
+
+    #[test]
+    fn successor_test() {
+        assert_eq!(rocksdb_kv::successor(b"ab"), Some(b"ac".to_vec()));
+        assert_eq!(rocksdb_kv::successor(b"a\xff"), Some(b"b".to_vec()));
+        assert_eq!(rocksdb_kv::successor(b"\xff\xff"), None);
+    }
+
+    #[test]
+    fn reject_invalid_utf8_path_test() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        use std::path::Path;
+        match rocksdb_kv::RocksDBKV::new(Path::new(OsStr::from_bytes(b"db\xff"))) {
+            Err(db::Error::Encoding(_)) => (),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn reject_invalid_utf8_value_test() {
+        use db::KV;
+        use db::rocksdb_kv::rocksdb::Writable;
+        let db = new_temp_db();
+        db.rocks_db.put(b"key", b"\xff").unwrap();
+        match db.get(&"key".to_owned()) {
+            Err(db::Error::Encoding(_)) => (),
+            _ => panic!(),
+        }
+        assert!(db.scan(db::Scan::all()).next().unwrap().is_err());
+    }
 
     fn new_temp_db() -> rocksdb_kv::RocksDBKV {
         extern crate tempdir;
         let path = tempdir::TempDir::new("rocksdb_kv_test").unwrap().path().join("db");
-        rocksdb_kv::RocksDBKV::new(path.as_path())
+        rocksdb_kv::RocksDBKV::new(path.as_path()).unwrap()
     }
 
     extern crate rand;
@@ -0,0 +1,446 @@
+extern crate rusqlite;
+
+use db;
+use db::hashmap_kv::HashMapKV;
+use self::rusqlite::Connection;
+use self::rusqlite::types::ToSql;
+use std::path::{Path, PathBuf};
+
+const SCHEMA: &'static str = "
+    CREATE TABLE IF NOT EXISTS kv (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+    CREATE TABLE IF NOT EXISTS lines (
+        key TEXT PRIMARY KEY, channel TEXT NOT NULL, tag TEXT NOT NULL,
+        user TEXT NOT NULL, time INTEGER NOT NULL, line TEXT NOT NULL);
+    CREATE INDEX IF NOT EXISTS lines_by_channel ON lines (channel, time);
+    CREATE INDEX IF NOT EXISTS lines_by_user ON lines (user, time);
+    CREATE VIRTUAL TABLE IF NOT EXISTS lines_fts USING fts4(line);";
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct IndexedLine {
+    pub channel: String,
+    pub tag: String,
+    pub user: String,
+    pub time: i64,
+    pub line: String,
+}
+
+pub type Indexer = fn(&str, &str) -> Option<IndexedLine>;
+
+/// Results are newest first.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct LineQuery {
+    pub channel: Option<String>,
+    pub tag: Option<String>,
+    pub user: Option<String>,
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+    pub text: Option<String>,
+    pub limit: Option<usize>,
+}
+
+impl LineQuery {
+    pub fn new() -> LineQuery {
+        LineQuery::default()
+    }
+
+    pub fn channel(self, channel: &str) -> LineQuery {
+        LineQuery { channel: Some(channel.to_owned()), .. self }
+    }
+
+    pub fn tag(self, tag: &str) -> LineQuery {
+        LineQuery { tag: Some(tag.to_owned()), .. self }
+    }
+
+    pub fn user(self, user: &str) -> LineQuery {
+        LineQuery { user: Some(user.to_owned()), .. self }
+    }
+
+    pub fn since(self, since: i64) -> LineQuery {
+        LineQuery { since: Some(since), .. self }
+    }
+
+    pub fn until(self, until: i64) -> LineQuery {
+        LineQuery { until: Some(until), .. self }
+    }
+
+    pub fn text(self, text: &str) -> LineQuery {
+        LineQuery { text: Some(text.to_owned()), .. self }
+    }
+
+    pub fn limit(self, limit: usize) -> LineQuery {
+        LineQuery { limit: Some(limit), .. self }
+    }
+}
+
+pub struct SqliteKV {
+    conn: Connection,
+    indexer: Indexer,
+    path: Option<PathBuf>,
+}
+
+// Reads through a connection of its own, so one SELECT sees a consistent
+// store while the owner keeps writing.
+struct SqliteSnapshot {
+    path: PathBuf,
+    indexer: Indexer,
+}
+
+impl db::Snapshot for SqliteSnapshot {
+    fn copy_to(&self, to: &mut db::KV<String, String>) -> Result<usize, db::Error> {
+        let kv = try!(SqliteKV::open(&self.path, self.indexer));
+        db::copy(&kv, to)
+    }
+}
+
+fn sql_error(err: rusqlite::Error) -> db::Error {
+    db::Error::Io(err.to_string())
+}
+
+impl SqliteKV {
+    pub fn open(path: &Path, indexer: Indexer) -> Result<SqliteKV, db::Error> {
+        SqliteKV::setup(try!(Connection::open(path).map_err(sql_error)), indexer, Some(path.to_path_buf()))
+    }
+
+    pub fn open_in_memory(indexer: Indexer) -> Result<SqliteKV, db::Error> {
+        SqliteKV::setup(try!(Connection::open_in_memory().map_err(sql_error)), indexer, None)
+    }
+
+    fn setup(conn: Connection, indexer: Indexer, path: Option<PathBuf>) -> Result<SqliteKV, db::Error> {
+        try!(conn.execute_batch(SCHEMA).map_err(sql_error));
+        Ok(SqliteKV { conn: conn, indexer: indexer, path: path })
+    }
+
+    pub fn query(&self, query: &LineQuery) -> Result<Vec<(String, String)>, db::Error> {
+        let limit = query.limit.map(|l| l as i64);
+        let mut sql = "SELECT kv.key, kv.value FROM lines JOIN kv ON kv.key = lines.key WHERE 1".to_owned();
+        let mut params: Vec<&ToSql> = Vec::new();
+        if let Some(ref channel) = query.channel {
+            sql.push_str(" AND lines.channel = ?");
+            params.push(channel);
+        }
+        if let Some(ref tag) = query.tag {
+            sql.push_str(" AND lines.tag = ?");
+            params.push(tag);
+        }
+        if let Some(ref user) = query.user {
+            sql.push_str(" AND lines.user = ?");
+            params.push(user);
+        }
+        if let Some(ref since) = query.since {
+            sql.push_str(" AND lines.time >= ?");
+            params.push(since);
+        }
+        if let Some(ref until) = query.until {
+            sql.push_str(" AND lines.time < ?");
+            params.push(until);
+        }
+        if let Some(ref text) = query.text {
+            sql.push_str(" AND lines.rowid IN (SELECT docid FROM lines_fts WHERE lines_fts MATCH ?)");
+            params.push(text);
+        }
+        sql.push_str(" ORDER BY lines.time DESC, lines.key");
+        if let Some(ref limit) = limit {
+            sql.push_str(" LIMIT ?");
+            params.push(limit);
+        }
+        self.select_pairs(&sql, &params)
+    }
+
+    fn select_pairs(&self, sql: &str, params: &[&ToSql]) -> Result<Vec<(String, String)>, db::Error> {
+        let mut stmt = try!(self.conn.prepare(sql).map_err(sql_error));
+        let rows = try!(stmt.query_map(params, |row| {
+            let key: String = row.get(0);
+            let value: String = row.get(1);
+            (key, value)
+        }).map_err(sql_error));
+        let pairs = rows.map(|row| row.map_err(sql_error)).collect();
+        pairs
+    }
+
+    fn _scan(&self, scan: &db::Scan<String>) -> Result<Vec<(String, String)>, db::Error> {
+        let limit = scan.limit.map(|l| l as i64);
+        let past_prefix = scan.prefix.as_ref().and_then(|p| successor(p));
+        let mut sql = "SELECT key, value FROM kv WHERE 1".to_owned();
+        let mut params: Vec<&ToSql> = Vec::new();
+        if let Some(ref prefix) = scan.prefix {
+            sql.push_str(" AND key >= ?");
+            params.push(prefix);
+        }
+        if let Some(ref past_prefix) = past_prefix {
+            sql.push_str(" AND key < ?");
+            params.push(past_prefix);
+        }
+        if let Some(ref start) = scan.start {
+            sql.push_str(" AND key >= ?");
+            params.push(start);
+        }
+        if let Some(ref end) = scan.end {
+            sql.push_str(" AND key < ?");
+            params.push(end);
+        }
+        sql.push_str(if scan.reverse { " ORDER BY key DESC" } else { " ORDER BY key" });
+        if let Some(ref limit) = limit {
+            sql.push_str(" LIMIT ?");
+            params.push(limit);
+        }
+        self.select_pairs(&sql, &params)
+    }
+
+    fn unindex(&self, key: &String) -> Result<(), db::Error> {
+        try!(self.conn.execute("DELETE FROM lines_fts WHERE docid IN (SELECT rowid FROM lines WHERE key = ?)", &[key]).map_err(sql_error));
+        try!(self.conn.execute("DELETE FROM lines WHERE key = ?", &[key]).map_err(sql_error));
+        Ok(())
+    }
+
+    fn index(&self, key: &String, l: &IndexedLine) -> Result<(), db::Error> {
+        try!(self.conn.execute(
+                "INSERT INTO lines (key, channel, tag, user, time, line) VALUES (?, ?, ?, ?, ?, ?)",
+                &[key, &l.channel, &l.tag, &l.user, &l.time, &l.line]).map_err(sql_error));
+        let rowid = self.conn.last_insert_rowid();
+        try!(self.conn.execute("INSERT INTO lines_fts (docid, line) VALUES (?, ?)", &[&rowid, &l.line]).map_err(sql_error));
+        Ok(())
+    }
+
+    fn apply(&self, batch: db::Batch<String, String>) -> Result<(), db::Error> {
+        for op in batch.into_ops() {
+            match op {
+                db::BatchOp::Put(k, v) => {
+                    try!(self.conn.execute("INSERT OR REPLACE INTO kv (key, value) VALUES (?, ?)", &[&k, &v]).map_err(sql_error));
+                    try!(self.unindex(&k));
+                    if let Some(line) = (self.indexer)(&k, &v) {
+                        try!(self.index(&k, &line));
+                    }
+                },
+                db::BatchOp::Delete(k) => {
+                    try!(self.conn.execute("DELETE FROM kv WHERE key = ?", &[&k]).map_err(sql_error));
+                    try!(self.unindex(&k));
+                },
+            }
+        }
+        Ok(())
+    }
+
+    fn _write(&self, batch: db::Batch<String, String>) -> Result<(), db::Error> {
+        try!(self.conn.execute_batch("BEGIN").map_err(sql_error));
+        match self.apply(batch) {
+            Ok(()) => self.conn.execute_batch("COMMIT").map_err(sql_error),
+            Err(err) => {
+                let _ = self.conn.execute_batch("ROLLBACK");
+                Err(err)
+            },
+        }
+    }
+}
+
+// A range bound, since `length()` and `substr()` stop at the NULs in keys.
+fn successor(prefix: &str) -> Option<String> {
+    let mut chars: Vec<char> = prefix.chars().collect();
+    while let Some(last) = chars.pop() {
+        let mut next = last as u32 + 1;
+        while next <= 0x10ffff {
+            if let Some(c) = char::from_u32(next) {
+                chars.push(c);
+                return Some(chars.into_iter().collect())
+            }
+            next += 1
+        }
+    }
+    None
+}
+
+impl db::KV<String, String> for SqliteKV {
+    fn put(&mut self, key: &String, value: &String) -> Result<(), db::Error> {
+        let mut batch = db::Batch::new();
+        batch.put(key.clone(), value.clone());
+        self._write(batch)
+    }
+
+    fn get(&self, key: &String) -> Result<Option<String>, db::Error> {
+        match self.conn.query_row("SELECT value FROM kv WHERE key = ?", &[key], |row| row.get(0)) {
+            Ok(value) => Ok(Some(value)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+
+    fn scan<'a>(&'a self, scan: db::Scan<String>) -> db::ScanIter<'a, String, String> {
+        match self._scan(&scan) {
+            Ok(pairs) => Box::new(pairs.into_iter().map(Ok)),
+            Err(err) => Box::new(Some(Err(err)).into_iter()),
+        }
+    }
+
+    fn remove(&mut self, key: &String) -> Result<(), db::Error> {
+        let mut batch = db::Batch::new();
+        batch.delete(key.clone());
+        self._write(batch)
+    }
+
+    fn write(&mut self, batch: db::Batch<String, String>) -> Result<(), db::Error> {
+        self._write(batch)
+    }
+
+    fn snapshot(&self) -> Result<Box<db::Snapshot>, db::Error> {
+        match self.path {
+            Some(ref path) => Ok(Box::new(SqliteSnapshot { path: path.clone(), indexer: self.indexer })),
+            None => {
+                let mut copy = HashMapKV::new();
+                try!(db::copy(self, &mut copy));
+                Ok(Box::new(copy))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use db;
+    use db::KV;
+    use db::sqlite_kv::*;
+
+    #[test]
+    fn get_nonexistent_key_test() {
+        db::test::get_nonexistent_key_test(new_db(), rand_key())
+    }
+
+    #[test]
+    fn put_and_get_key_test() {
+        db::test::put_and_get_key_test(new_db(), rand_key(), rand_value())
+    }
+
+    #[test]
+    fn overwrite_key_test() {
+        db::test::overwrite_key_test(new_db(), rand_key(), rand_value(), rand_value())
+    }
+
+    #[test]
+    fn remove_key_test() {
+        db::test::remove_key_test(new_db(), rand_key(), rand_value())
+    }
+
+    #[test]
+    fn fetch_keys_by_prefix_test() {
+        let prefix = "prefix".to_owned();
+        db::test::fetch_keys_by_prefix_test(new_db(), prefix, rand_key, prefixed_key, rand_value)
+    }
+
+    #[test]
+    fn write_batch_test() {
+        db::test::write_batch_test(new_db(), rand_key(), rand_key(), rand_key(), rand_value(), rand_value())
+    }
+
+    #[test]
+    fn write_batch_in_order_test() {
+        db::test::write_batch_in_order_test(new_db(), rand_key(), rand_key(), rand_value())
+    }
+
+    #[test]
+    fn fetch_keys_in_key_order_test() {
+        db::test::fetch_keys_in_key_order_test(new_db())
+    }
+
+    #[test]
+    fn scan_range_test() {
+        db::test::scan_range_test(new_db())
+    }
+
+    #[test]
+    fn scan_reverse_test() {
+        db::test::scan_reverse_test(new_db())
+    }
+
+    #[test]
+    fn scan_with_limit_test() {
+        db::test::scan_with_limit_test(new_db())
+    }
+
+    #[test]
+    fn fetch_tuple_keys_by_prefix_test() {
+        db::test::fetch_tuple_keys_by_prefix_test(new_db())
+    }
+
+    #[test]
+    fn copy_snapshot_test() {
+        db::test::copy_snapshot_test(new_db())
+    }
+
+    #[test]
+    fn copy_snapshot_of_file_test() {
+        extern crate tempdir;
+        let dir = tempdir::TempDir::new("sqlite_kv_test").unwrap();
+        db::test::copy_snapshot_test(SqliteKV::open(&dir.path().join("db.sqlite"), split_indexer).unwrap())
+    }
+
+    #[test]
+    fn successor_test() {
+        assert_eq!(successor("line\u{0}#c\u{0}"), Some("line\u{0}#c\u{1}".to_owned()));
+        assert_eq!(successor("a\u{d7ff}"), Some("a\u{e000}".to_owned()));
+        assert_eq!(successor("a\u{10ffff}"), Some("b".to_owned()));
+        assert_eq!(successor(""), None);
+    }
+
+    #[test]
+    fn query_lines_test() {
+        let mut db = new_db();
+        db.put(&"line-1".to_owned(), &"#a|#deploy|alice|100|rolled out the release".to_owned()).unwrap();
+        db.put(&"line-2".to_owned(), &"#a|#deploy|bob|200|release broke staging".to_owned()).unwrap();
+        db.put(&"line-3".to_owned(), &"#a|#idea|alice|300|cache the release notes".to_owned()).unwrap();
+        db.put(&"line-4".to_owned(), &"#b|#deploy|alice|400|unrelated".to_owned()).unwrap();
+        db.put(&"other".to_owned(), &"#a|#deploy|alice|500|not a line".to_owned()).unwrap();
+
+        let keys = |query: LineQuery| db.query(&query).unwrap().into_iter().map(|(k, _)| k).collect::<Vec<String>>();
+        assert_eq!(keys(LineQuery::new()), vec!["line-4", "line-3", "line-2", "line-1"]);
+        assert_eq!(keys(LineQuery::new().channel("#a").user("alice")), vec!["line-3", "line-1"]);
+        assert_eq!(keys(LineQuery::new().tag("#deploy").since(200).until(400)), vec!["line-2"]);
+        assert_eq!(keys(LineQuery::new().channel("#a").text("release")), vec!["line-3", "line-2", "line-1"]);
+        assert_eq!(keys(LineQuery::new().text("release").limit(1)), vec!["line-3"]);
+    }
+
+    #[test]
+    fn keep_index_in_step_with_writes_test() {
+        let mut db = new_db();
+        db.put(&"line-1".to_owned(), &"#a|#deploy|alice|100|rolled out the release".to_owned()).unwrap();
+        db.put(&"line-1".to_owned(), &"#a|#deploy|alice|100|rolled back".to_owned()).unwrap();
+        assert!(db.query(&LineQuery::new().text("release")).unwrap().is_empty());
+        assert_eq!(db.query(&LineQuery::new().text("back")).unwrap().len(), 1);
+
+        db.remove(&"line-1".to_owned()).unwrap();
+        assert!(db.query(&LineQuery::new()).unwrap().is_empty());
+        assert!(db.query(&LineQuery::new().text("back")).unwrap().is_empty());
+    }
+
+    fn split_indexer(key: &str, value: &str) -> Option<IndexedLine> {
+        let fields = value.split('|').collect::<Vec<&str>>();
+        match (key.starts_with("line-"), fields.len(), fields.get(3).and_then(|t| t.parse().ok())) {
+            (true, 5, Some(time)) => Some(IndexedLine {
+                channel: fields[0].to_owned(),
+                tag: fields[1].to_owned(),
+                user: fields[2].to_owned(),
+                time: time,
+                line: fields[4].to_owned() }),
+            _ => None,
+        }
+    }
+
+    fn new_db() -> SqliteKV {
+        SqliteKV::open_in_memory(split_indexer).unwrap()
+    }
+
+    extern crate rand;
+
+    fn rand_key() -> String {
+        use self::rand::Rng;
+        let salt: String = rand::thread_rng().gen_ascii_chars().take(5).collect();
+        format!("key{}", salt)
+    }
+
+    fn rand_value() -> String {
+        use self::rand::Rng;
+        let salt: String = rand::thread_rng().gen_ascii_chars().take(5).collect();
+        format!("value{}", salt)
+    }
+
+    fn prefixed_key() -> String {
+        format!("prefix-{}", rand_key())
+    }
+}
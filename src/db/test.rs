@@ -1,4 +1,7 @@
 use db;
+use db::hashmap_kv::HashMapKV;
+use db::key::Tuple;
+use db::typed::Key;
 use std::fmt::Debug;
 
 pub fn get_nonexistent_key_test<K: Eq + Debug, V: Eq + Debug, T: db::KV<K, V>>(db: T, k: K) {
@@ -26,7 +29,7 @@ pub fn remove_key_test<K: Eq + Debug, V: Eq + Debug, T: db::KV<K, V>>(mut db: T,
 }
 
 pub fn fetch_keys_by_prefix_test<K, V, DB, KGen, PKGen, VGen>(mut db: DB, prefix: K, non_prefixed_key:  KGen, prefixed_key: PKGen, value: VGen) -> ()
-    where K: Eq + Debug + Ord, V: Eq + Debug + Ord,
+    where K: Eq + Debug + Ord + Clone, V: Eq + Debug + Ord,
           KGen: Fn() -> K, PKGen: Fn() -> K, VGen: Fn() -> V,
           DB: db::KV<K, V>
 {
@@ -40,5 +43,118 @@ pub fn fetch_keys_by_prefix_test<K, V, DB, KGen, PKGen, VGen>(mut db: DB, prefix
     assert_eq!(db.put(&k3, &v3), Ok(()));
     assert_eq!(db.put(&k4, &v4), Ok(()));
 
-    assert_eq!(db.get_prefix(&prefix).sort(), vec![(k2, v2), (k4, v4)].sort())
+    let mut expected = vec![(k2, v2), (k4, v4)];
+    expected.sort();
+    assert_eq!(db.get_prefix(&prefix), Ok(expected))
+}
+
+pub fn fetch_keys_in_key_order_test<T: db::KV<String, String>>(mut db: T) {
+    put_keys(&mut db, &["p-b", "p-\u{e4}", "p-a", "q", "p-B", "p-ab", "o"]);
+
+    let expected = ["p-B", "p-a", "p-ab", "p-b", "p-\u{e4}"].iter()
+        .map(|k| (k.to_string(), format!("value-{}", k)))
+        .collect::<Vec<(String, String)>>();
+    assert_eq!(db.get_prefix(&"p-".to_owned()), Ok(expected))
+}
+
+pub fn write_batch_test<K: Eq + Debug + Clone, V: Eq + Debug + Clone, T: db::KV<K, V>>(mut db: T, k1: K, k2: K, k3: K, v1: V, v2: V) {
+    assert_eq!(db.put(&k3, &v1), Ok(()));
+
+    let mut batch = db::Batch::new();
+    batch.put(k1.clone(), v1.clone());
+    batch.put(k2.clone(), v2.clone());
+    batch.delete(k3.clone());
+    assert_eq!(batch.len(), 3);
+    assert_eq!(db.write(batch), Ok(()));
+
+    assert_eq!(db.get(&k1), Ok(Some(v1)));
+    assert_eq!(db.get(&k2), Ok(Some(v2)));
+    assert_eq!(db.get(&k3), Ok(None))
+}
+
+pub fn write_batch_in_order_test<K: Eq + Debug + Clone, V: Eq + Debug + Clone, T: db::KV<K, V>>(mut db: T, k1: K, k2: K, v: V) {
+    let mut batch = db::Batch::new();
+    batch.put(k1.clone(), v.clone());
+    batch.delete(k1.clone());
+    batch.delete(k2.clone());
+    batch.put(k2.clone(), v.clone());
+    assert_eq!(db.write(batch), Ok(()));
+
+    assert_eq!(db.get(&k1), Ok(None));
+    assert_eq!(db.get(&k2), Ok(Some(v)))
+}
+
+fn put_keys<T: db::KV<String, String>>(db: &mut T, keys: &[&str]) {
+    for k in keys {
+        assert_eq!(db.put(&k.to_string(), &format!("value-{}", k)), Ok(()));
+    }
+}
+
+fn scanned_keys<T: db::KV<String, String>>(db: &T, scan: db::Scan<String>) -> Vec<String> {
+    db.scan(scan).map(|r| r.unwrap().0).collect()
+}
+
+fn strings(keys: &[&str]) -> Vec<String> {
+    keys.iter().map(|k| k.to_string()).collect()
+}
+
+pub fn scan_range_test<T: db::KV<String, String>>(mut db: T) {
+    put_keys(&mut db, &["a", "b", "ba", "bb", "bc", "c"]);
+
+    assert_eq!(scanned_keys(&db, db::Scan::all()), strings(&["a", "b", "ba", "bb", "bc", "c"]));
+    assert_eq!(scanned_keys(&db, db::Scan::prefix("b".to_owned())), strings(&["b", "ba", "bb", "bc"]));
+    assert_eq!(scanned_keys(&db, db::Scan::all().start("ab".to_owned()).end("bb".to_owned())), strings(&["b", "ba"]));
+    assert_eq!(scanned_keys(&db, db::Scan::prefix("b".to_owned()).start("bb".to_owned())), strings(&["bb", "bc"]));
+    assert_eq!(scanned_keys(&db, db::Scan::prefix("d".to_owned())), strings(&[]));
+    assert_eq!(scanned_keys(&db, db::Scan::prefix("a".to_owned()).start("b".to_owned())), strings(&[]));
+
+    let pairs: Result<Vec<(String, String)>, db::Error> = db.scan(db::Scan::prefix("c".to_owned())).collect();
+    assert_eq!(pairs, Ok(vec![("c".to_owned(), "value-c".to_owned())]))
+}
+
+pub fn scan_reverse_test<T: db::KV<String, String>>(mut db: T) {
+    put_keys(&mut db, &["a", "b", "ba", "bb", "bc", "c"]);
+
+    assert_eq!(scanned_keys(&db, db::Scan::all().reverse()), strings(&["c", "bc", "bb", "ba", "b", "a"]));
+    assert_eq!(scanned_keys(&db, db::Scan::prefix("b".to_owned()).reverse()), strings(&["bc", "bb", "ba", "b"]));
+    assert_eq!(scanned_keys(&db, db::Scan::prefix("c".to_owned()).reverse()), strings(&["c"]));
+    assert_eq!(scanned_keys(&db, db::Scan::all().end("bb".to_owned()).reverse()), strings(&["ba", "b", "a"]));
+    assert_eq!(scanned_keys(&db, db::Scan::all().start("b".to_owned()).end("bz".to_owned()).reverse()), strings(&["bc", "bb", "ba", "b"]));
+    assert_eq!(scanned_keys(&db, db::Scan::all().start("c".to_owned()).end("b".to_owned()).reverse()), strings(&[]));
+}
+
+pub fn scan_with_limit_test<T: db::KV<String, String>>(mut db: T) {
+    put_keys(&mut db, &["a", "b", "ba", "bb", "bc", "c"]);
+
+    assert_eq!(scanned_keys(&db, db::Scan::prefix("b".to_owned()).limit(2)), strings(&["b", "ba"]));
+    assert_eq!(scanned_keys(&db, db::Scan::prefix("b".to_owned()).reverse().limit(2)), strings(&["bc", "bb"]));
+    assert_eq!(scanned_keys(&db, db::Scan::all().limit(0)), strings(&[]));
+}
+
+pub fn fetch_tuple_keys_by_prefix_test<T: db::KV<String, String>>(mut db: T) {
+    let key = |components: &[&str]| components.iter().fold(Tuple::new(), |t, c| t.push(c)).encode();
+    let keys = vec![
+        key(&["line", "#c", "#tag", "1"]),
+        key(&["line", "#c", "#tag", "2"]),
+        key(&["line", "#c", "#tag2", "3"]),
+        key(&["line", "#c2", "#tag", "4"]),
+        key(&["meta", "schema-version"])];
+    for k in keys.iter() {
+        assert_eq!(db.put(k, &format!("value-{}", k)), Ok(()));
+    }
+
+    let tag_prefix = Tuple::new().push("line").push("#c").push("#tag").encode();
+    assert_eq!(scanned_keys(&db, db::Scan::prefix(tag_prefix.clone())), vec![keys[0].clone(), keys[1].clone()]);
+    assert_eq!(scanned_keys(&db, db::Scan::prefix(tag_prefix).reverse()), vec![keys[1].clone(), keys[0].clone()]);
+    let channel_prefix = Tuple::new().push("line").push("#c").encode();
+    assert_eq!(scanned_keys(&db, db::Scan::prefix(channel_prefix)), keys[..3].to_vec());
+    assert_eq!(scanned_keys(&db, db::Scan::prefix(Tuple::new().push("line").encode())), keys[..4].to_vec());
+}
+
+pub fn copy_snapshot_test<T: db::KV<String, String>>(mut db: T) {
+    put_keys(&mut db, &["a", "b", "c"]);
+    let snapshot = db.snapshot().unwrap();
+    let mut copy = HashMapKV::new();
+    assert_eq!(snapshot.copy_to(&mut copy), Ok(3));
+    assert_eq!(scanned_keys(&copy, db::Scan::all()), strings(&["a", "b", "c"]));
 }
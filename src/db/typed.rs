@@ -0,0 +1,191 @@
+extern crate serde;
+extern crate serde_json;
+
+use db;
+use self::serde::{Serialize, Deserialize};
+
+pub trait Key {
+    fn encode(&self) -> String;
+}
+
+impl Key for String {
+    fn encode(&self) -> String { self.clone() }
+}
+
+impl <'a> Key for &'a str {
+    fn encode(&self) -> String { (*self).to_owned() }
+}
+
+pub fn encode<T>(key: &str, value: &T) -> Result<String, db::Error> where T: Serialize {
+    serde_json::to_string(value)
+        .map_err(|e| db::Error::Encoding(format!("unable to encode value of key {}: {}", key, e)))
+}
+
+pub fn decode<T>(key: &str, json: &str) -> Result<T, db::Error> where T: Deserialize {
+    serde_json::from_str(json)
+        .map_err(|e| db::Error::Encoding(format!("unable to decode value of key {}: {}", key, e)))
+}
+
+pub struct Batch {
+    inner: db::Batch<String, String>,
+}
+
+impl Batch {
+    pub fn new() -> Batch {
+        Batch { inner: db::Batch::new() }
+    }
+
+    pub fn put<T>(&mut self, key: &Key, value: &T) -> Result<(), db::Error> where T: Serialize {
+        let key = key.encode();
+        let json = try!(encode(&key, value));
+        self.inner.put(key, json);
+        Ok(())
+    }
+
+    pub fn put_raw(&mut self, key: &Key, value: String) {
+        self.inner.put(key.encode(), value)
+    }
+
+    pub fn delete(&mut self, key: &Key) {
+        self.inner.delete(key.encode())
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+}
+
+pub struct Store<KV> {
+    kv: KV,
+}
+
+impl <KV> Store<KV> where KV: db::KV<String, String> {
+    pub fn new(kv: KV) -> Store<KV> {
+        Store { kv: kv }
+    }
+
+    pub fn kv(&self) -> &KV {
+        &self.kv
+    }
+
+    pub fn kv_mut(&mut self) -> &mut KV {
+        &mut self.kv
+    }
+
+    pub fn into_inner(self) -> KV {
+        self.kv
+    }
+
+    pub fn get<T>(&self, key: &Key) -> Result<Option<T>, db::Error> where T: Deserialize {
+        let key = key.encode();
+        match try!(self.kv.get(&key)) {
+            Some(json) => decode(&key, &json).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn put<T>(&mut self, key: &Key, value: &T) -> Result<(), db::Error> where T: Serialize {
+        let key = key.encode();
+        let json = try!(encode(&key, value));
+        self.kv.put(&key, &json)
+    }
+
+    pub fn remove(&mut self, key: &Key) -> Result<(), db::Error> {
+        self.kv.remove(&key.encode())
+    }
+
+    /// Yields an `Error::Encoding` for each value that does not decode.
+    pub fn scan<'a, T>(&'a self, scan: db::Scan<String>) -> Box<Iterator<Item=Result<(String, T), db::Error>> + 'a> where T: Deserialize + 'a {
+        Box::new(self.kv.scan(scan).map(|pair| pair.and_then(|(key, json)| {
+            let value = try!(decode(&key, &json));
+            Ok((key, value))
+        })))
+    }
+
+    pub fn write(&mut self, batch: Batch) -> Result<(), db::Error> {
+        self.kv.write(batch.inner)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use db;
+    use db::KV;
+    use db::typed::*;
+    use db::hashmap_kv::HashMapKV;
+
+    extern crate serde;
+    use self::serde::{Serialize, Serializer, Deserialize, Deserializer};
+
+    #[derive(Debug, PartialEq)]
+    struct Point { x: i64, y: i64 }
+
+    impl Serialize for Point {
+        fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error> where S: Serializer {
+            (self.x, self.y).serialize(serializer)
+        }
+    }
+
+    impl Deserialize for Point {
+        fn deserialize<D>(deserializer: &mut D) -> Result<Point, D::Error> where D: Deserializer {
+            let (x, y) = try!(<(i64, i64) as Deserialize>::deserialize(deserializer));
+            Ok(Point { x: x, y: y })
+        }
+    }
+
+    #[test]
+    fn put_and_get_typed_value_test() {
+        let mut store = Store::new(HashMapKV::new());
+        assert_eq!(store.get::<Point>(&"p"), Ok(None));
+        assert_eq!(store.put(&"p", &Point { x: 1, y: 2 }), Ok(()));
+        assert_eq!(store.get::<Point>(&"p"), Ok(Some(Point { x: 1, y: 2 })));
+        assert_eq!(store.kv().get(&"p".to_owned()), Ok(Some("[1,2]".to_owned())));
+        assert_eq!(store.remove(&"p"), Ok(()));
+        assert_eq!(store.get::<Point>(&"p"), Ok(None));
+    }
+
+    #[test]
+    fn surface_decode_errors_test() {
+        let mut store = Store::new(HashMapKV::new());
+        store.kv_mut().put(&"p".to_owned(), &"not a point".to_owned()).unwrap();
+        match store.get::<Point>(&"p") {
+            Err(db::Error::Encoding(_)) => (),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn scan_typed_values_test() {
+        let mut store = Store::new(HashMapKV::new());
+        store.put(&"p-1", &Point { x: 1, y: 1 }).unwrap();
+        store.kv_mut().put(&"p-2".to_owned(), &"not a point".to_owned()).unwrap();
+        store.put(&"p-3", &Point { x: 3, y: 3 }).unwrap();
+        store.put(&"q-1", &Point { x: 4, y: 4 }).unwrap();
+
+        let scanned = store.scan::<Point>(db::Scan::prefix("p-".to_owned())).collect::<Vec<_>>();
+        assert_eq!(scanned.len(), 3);
+        assert_eq!(scanned[0], Ok(("p-1".to_owned(), Point { x: 1, y: 1 })));
+        assert!(scanned[1].is_err());
+        assert_eq!(scanned[2], Ok(("p-3".to_owned(), Point { x: 3, y: 3 })));
+    }
+
+    #[test]
+    fn write_typed_batch_test() {
+        let mut store = Store::new(HashMapKV::new());
+        store.put(&"old", &Point { x: 0, y: 0 }).unwrap();
+
+        let mut batch = Batch::new();
+        batch.put(&"p", &Point { x: 1, y: 2 }).unwrap();
+        batch.put_raw(&"index", "p".to_owned());
+        batch.delete(&"old");
+        assert_eq!(store.write(batch), Ok(()));
+
+        assert_eq!(store.get::<Point>(&"p"), Ok(Some(Point { x: 1, y: 2 })));
+        assert_eq!(store.kv().get(&"index".to_owned()), Ok(Some("p".to_owned())));
+        assert_eq!(store.get::<Point>(&"old"), Ok(None));
+    }
+}
@@ -2,67 +2,585 @@ extern crate irc;
 use self::irc::client::prelude::*;
 use self::irc::client::data::user::User;
 
+extern crate rand;
+
 use free_runner::*;
+use outbox::{Limits, Outbox};
+
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Sender, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const BACKOFF_INITIAL_MS: u64 = 1000;
+const BACKOFF_MAX_MS: u64 = 5 * 60 * 1000;
+const PING_INTERVAL_SECS: u64 = 60;
+const PING_TIMEOUT_SECS: u64 = 180;
+const WATCHDOG_TICK_SECS: u64 = 5;
+const READER_SHUTDOWN_SECS: u64 = 30;
+const OUTBOX_MIN_WAIT_MS: u64 = 10;
+const OUTBOX_DISCONNECTED_WAIT_MS: u64 = 1000;
+const MAX_LINE_LEN: usize = 512;
+const MAX_USER_LEN: usize = 10;
+const MAX_HOST_LEN: usize = 63;
+const DEFAULT_PREFIX_LEN: usize = 30 + 1 + MAX_USER_LEN + 1 + MAX_HOST_LEN;
+const MIN_SPLIT_BUDGET: usize = 32;
+const MORE: &'static str = " ...";
+const CONTINUED: &'static str = "... ";
 
 #[derive(Debug, PartialEq)]
 pub enum ChatEvent {
+    Connected { nick: String },
+    Disconnected,
+    ServerSupport { tokens: Vec<String> },
     ChannelMsg { channel: String, from: String, msg: String },
     PrivateMsg { from: String, msg: String },
     JoinedChannel { channel: String, who: String },
     PartedChannel { channel: String, who: String, comment: Option<String> },
+    ChannelNames { channel: String, names: Vec<String> },
+    ChannelNamesEnd { channel: String },
+    ChannelMode { channel: String, by: String, modes: String, args: Vec<String> },
+    ChannelAction { channel: String, from: String, msg: String },
+    PrivateAction { from: String, msg: String },
+    NickChanged { old: String, new: String },
+    Quit { who: String, reason: Option<String> },
+    Kicked { channel: String, who: String, by: String, reason: Option<String> },
+    TopicChanged { channel: String, by: String, topic: String },
+    Notice { from: String, to: String, msg: String },
 }
 
 #[derive(Debug, PartialEq)]
 pub enum ChatEffect {
     ChannelMsg { channel: String, msg: Vec<String> },
     PrivateMsg { to: String, msg: Vec<String> },
+    Many(Vec<ChatEffect>),
+}
+
+pub fn line_budget(prefix_len: usize, target: &str) -> usize {
+    MAX_LINE_LEN.saturating_sub(1 + prefix_len + " PRIVMSG ".len() + target.len() + " :\r\n".len())
 }
 
-pub fn run<F, S: Send + 'static>(config: &str, f: F, s: S) where F: Fn(Event<ChatEvent>, &mut S) -> Option<Effect<ChatEffect, ()>> + Send + 'static  {
-    let server = IrcServer::new(config).unwrap();
-    server.identify().unwrap();
+fn estimated_prefix_len(nickname: &str) -> usize {
+    nickname.len() + 1 + MAX_USER_LEN + 1 + MAX_HOST_LEN
+}
 
-    let server_clone = server.clone();
-    let handle_chat_effect = move |eff| {
-        match eff {
-            ChatEffect::ChannelMsg { channel, msg } => {
-                for line in msg.iter() {
-                    server_clone.send_privmsg(channel.as_str(), line.as_str()).unwrap();
-                }
-                noop()
+pub fn split_line(line: &str, budget: usize) -> Vec<String> {
+    if line.len() <= budget {
+        return vec![line.to_owned()]
+    }
+
+    if budget < MIN_SPLIT_BUDGET {
+        return split_chars(line, budget)
+    }
+    let mut pieces = Vec::new();
+    let mut rest = line.trim_right();
+    let mut lead = "";
+    while lead.len() + rest.len() > budget {
+        let cut = split_point(rest, budget - lead.len() - MORE.len());
+        pieces.push(format!("{}{}{}", lead, rest[..cut].trim_right(), MORE));
+        rest = rest[cut..].trim_left();
+        lead = CONTINUED;
+    }
+    pieces.push(format!("{}{}", lead, rest));
+    pieces
+}
+
+fn split_chars(line: &str, budget: usize) -> Vec<String> {
+    let mut pieces = Vec::new();
+    let mut piece = String::new();
+    for c in line.chars() {
+        if !piece.is_empty() && piece.len() + c.len_utf8() > budget {
+            pieces.push(piece);
+            piece = String::new();
+        }
+        piece.push(c)
+    }
+    pieces.push(piece);
+    pieces
+}
+
+fn split_point(s: &str, room: usize) -> usize {
+    let mut end = room;
+    while !s.is_char_boundary(end) {
+        end -= 1
+    }
+    if s[end..].starts_with(' ') {
+        return end
+    }
+    match s[..end].rfind(' ') {
+        Some(i) if s[..i].trim().len() > 0 => i,
+        _ if end > 0 => end,
+        _ => s.char_indices().nth(1).map_or(s.len(), |(i, _)| i),
+    }
+}
+
+fn queue_chat_effect(outbox: &mut Outbox, eff: ChatEffect, now: Instant, prefix_len: usize) {
+    let split = |target: &str, msg: Vec<String>| -> Vec<String> {
+        let budget = line_budget(prefix_len, target);
+        msg.iter().flat_map(|line| split_line(line, budget)).collect()
+    };
+    match eff {
+        ChatEffect::ChannelMsg { channel, msg } => outbox.push(&channel, split(&channel, msg), now),
+        ChatEffect::PrivateMsg { to, msg } => outbox.push(&to, split(&to, msg), now),
+        ChatEffect::Many(effs) => {
+            for eff in effs {
+                queue_chat_effect(outbox, eff, now, prefix_len)
             }
-            ChatEffect::PrivateMsg { to, msg } => {
-                for line in msg.iter() {
-                    server_clone.send_privmsg(to.as_str(), line.as_str()).unwrap();
-                }
-                noop()
+        }
+    }
+}
+
+fn spawn_sender(outbox: Arc<(Mutex<Outbox>, Condvar)>, current: Arc<Mutex<Option<IrcServer>>>) {
+    thread::spawn(move || {
+        let (ref lock, ref wakeup) = *outbox;
+        let mut queue = lock.lock().unwrap();
+        let mut reported = 0;
+        loop {
+            let now = Instant::now();
+            let connected = match *current.lock().unwrap() {
+                Some(ref server) => {
+                    for (target, line) in queue.pop_ready(now) {
+                        if let Err(err) = server.send_privmsg(&target, &line) {
+                            println!("Unable to send to {}: {}", target, err)
+                        }
+                    }
+                    true
+                },
+                None => false,
+            };
+
+            let stats = queue.stats();
+            if stats.dropped > reported {
+                println!("Outbox: {}", stats);
+                reported = stats.dropped
+            }
+
+            let next = queue.next_ready(now);
+            queue = match next {
+                Some(wait) => {
+                    let floor = if connected { OUTBOX_MIN_WAIT_MS } else { OUTBOX_DISCONNECTED_WAIT_MS };
+                    wakeup.wait_timeout(queue, wait.max(Duration::from_millis(floor))).unwrap().0
+                },
+                None => wakeup.wait(queue).unwrap(),
             }
         }
-    };
+    });
+}
+
+pub struct Backoff {
+    initial_ms: u64,
+    max_ms: u64,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Backoff {
+        Backoff { initial_ms: millis(initial), max_ms: millis(max), attempt: 0 }
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        self.next_delay_with(rand::random::<f64>())
+    }
+
+    fn next_delay_with(&mut self, jitter: f64) -> Duration {
+        let step = self.initial_ms.saturating_mul(1 << self.attempt.min(31)).min(self.max_ms);
+        self.attempt = self.attempt.saturating_add(1);
+        Duration::from_millis(step / 2 + ((step / 2) as f64 * jitter) as u64)
+    }
+}
+
+fn millis(d: Duration) -> u64 {
+    d.as_secs() * 1000 + (d.subsec_nanos() / 1000000) as u64
+}
+
+#[derive(Debug, PartialEq)]
+enum Liveness {
+    Alive,
+    Ping,
+    Dead,
+}
+
+struct Watchdog {
+    last_seen: Instant,
+    pinged: bool,
+    interval: Duration,
+    timeout: Duration,
+}
+
+impl Watchdog {
+    fn new(now: Instant, interval: Duration, timeout: Duration) -> Watchdog {
+        Watchdog { last_seen: now, pinged: false, interval: interval, timeout: timeout }
+    }
+
+    fn seen(&mut self, now: Instant) {
+        self.last_seen = now;
+        self.pinged = false
+    }
+
+    fn check(&mut self, now: Instant) -> Liveness {
+        let quiet = now.duration_since(self.last_seen);
+        if quiet >= self.timeout {
+            Liveness::Dead
+        } else if quiet >= self.interval && !self.pinged {
+            self.pinged = true;
+            Liveness::Ping
+        } else {
+            Liveness::Alive
+        }
+    }
+}
+
+enum Incoming {
+    Message(u64, io::Result<Message>),
+    Closed(u64),
+}
+
+fn connect(config: &str) -> io::Result<IrcServer> {
+    let server = try!(IrcServer::new(config));
+    try!(server.identify());
+    Ok(server)
+}
 
-    let runner = Runner::new(f, handle_chat_effect, s);
+fn spawn_reader(generation: u64, server: IrcServer, tx: Sender<Incoming>) {
+    thread::spawn(move || {
+        for message in server.iter() {
+            if tx.send(Incoming::Message(generation, message)).is_err() {
+                return
+            }
+        }
+        let _ = tx.send(Incoming::Closed(generation));
+    });
+}
 
-    for maybe_message in server.iter() {
-        let nickname = server.current_nickname();
-        match maybe_message {
-            Ok(Message { prefix: Some(ref who), command: Command::PRIVMSG(ref to, ref msg), .. }) if to == nickname => {
-                let nickname = String::from(User::new(who).get_nickname());
-                runner.send(ChatEvent::PrivateMsg { from: nickname.clone(), msg: msg.clone() }).unwrap()
+// Returns whether the reader of `generation` has seen its connection close.
+fn supervise(generation: u64, server: &IrcServer, rx: &Receiver<Incoming>, runner: &Runner<ChatEvent, ()>, backoff: &mut Backoff, prefix_len: &AtomicUsize) -> bool {
+    let mut watchdog = Watchdog::new(Instant::now(), Duration::from_secs(PING_INTERVAL_SECS), Duration::from_secs(PING_TIMEOUT_SECS));
+    loop {
+        match rx.recv_timeout(Duration::from_secs(WATCHDOG_TICK_SECS)) {
+            Ok(Incoming::Message(g, message)) => if g == generation {
+                watchdog.seen(Instant::now());
+                match message {
+                    Ok(Message { command: Command::Response(Response::RPL_WELCOME, _, _), .. }) => {
+                        backoff.reset();
+                        runner.send(ChatEvent::Connected { nick: server.current_nickname().to_owned() }).unwrap()
+                    },
+                    Ok(message) => {
+                        match message {
+                            Message { prefix: Some(ref who), command: Command::JOIN(..), .. } =>
+                                if User::new(who).get_nickname() == server.current_nickname() {
+                                    prefix_len.store(who.len(), Ordering::Relaxed)
+                                },
+                            Message { prefix: Some(ref who), command: Command::NICK(ref new), .. } => {
+                                let old = User::new(who).get_nickname();
+                                if old == server.current_nickname() || new == server.current_nickname() {
+                                    prefix_len.store(who.len() - old.len() + new.len(), Ordering::Relaxed)
+                                }
+                            },
+                            _ => (),
+                        }
+                        dispatch(server, runner, message)
+                    },
+                    Err(err) => println!("{}", err),
+                }
             },
-            Ok(Message { prefix: Some(ref who), command: Command::PRIVMSG(ref to, ref msg), .. }) => {
-                let nickname = String::from(User::new(who).get_nickname());
-                runner.send(ChatEvent::ChannelMsg { channel: to.clone(), from: nickname.clone(), msg: msg.clone() }).unwrap()
+            Ok(Incoming::Closed(g)) => if g == generation {
+                println!("Connection closed");
+                return true
             },
-            Ok(Message { prefix: Some(ref who), command: Command::JOIN(ref channel, _, _), .. }) => {
-                let nickname = String::from(User::new(who).get_nickname());
-                runner.send(ChatEvent::JoinedChannel { channel: channel.clone(), who: nickname }).unwrap()
+            Err(RecvTimeoutError::Timeout) => match watchdog.check(Instant::now()) {
+                Liveness::Alive => (),
+                Liveness::Ping => if let Err(err) = server.send(Command::PING(server.current_nickname().to_owned(), None)) {
+                    println!("Unable to ping: {}", err)
+                },
+                Liveness::Dead => {
+                    println!("Ping timeout");
+                    let _ = server.send_quit("Ping timeout");
+                    return false
+                },
             },
-            Ok(Message { prefix: Some(ref who), command: Command::PART(ref channel, ref maybe_comment), .. }) => {
-                let nickname = String::from(User::new(who).get_nickname());
-                runner.send(ChatEvent::PartedChannel { channel: channel.clone(), who: nickname, comment: maybe_comment.clone() }).unwrap()
+            Err(RecvTimeoutError::Disconnected) => return false,
+        }
+    }
+}
+
+// Waits for the reader of `generation` to see its connection close, so a
+// dead connection is not left reading alongside the next one.
+fn retire_reader(generation: u64, rx: &Receiver<Incoming>, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return false
+        }
+        match rx.recv_timeout(deadline - now) {
+            Ok(Incoming::Closed(g)) if g == generation => return true,
+            Ok(_) => (),
+            Err(_) => return false,
+        }
+    }
+}
+
+fn ctcp_action(msg: &str) -> Option<&str> {
+    if msg.starts_with("\u{1}ACTION ") {
+        Some(msg["\u{1}ACTION ".len()..].trim_right_matches('\u{1}'))
+    } else {
+        None
+    }
+}
+
+fn dispatch(server: &IrcServer, runner: &Runner<ChatEvent, ()>, message: Message) {
+    let nickname = server.current_nickname();
+    match message {
+        Message { prefix: Some(ref who), command: Command::PRIVMSG(ref to, ref msg), .. } if ctcp_action(msg).is_some() => {
+            let from = String::from(User::new(who).get_nickname());
+            let msg = ctcp_action(msg).unwrap().to_owned();
+            if to == nickname {
+                runner.send(ChatEvent::PrivateAction { from: from, msg: msg }).unwrap()
+            } else {
+                runner.send(ChatEvent::ChannelAction { channel: to.clone(), from: from, msg: msg }).unwrap()
+            }
+        },
+        Message { prefix: Some(ref who), command: Command::PRIVMSG(ref to, ref msg), .. } if to == nickname => {
+            let nickname = String::from(User::new(who).get_nickname());
+            runner.send(ChatEvent::PrivateMsg { from: nickname.clone(), msg: msg.clone() }).unwrap()
+        },
+        Message { prefix: Some(ref who), command: Command::PRIVMSG(ref to, ref msg), .. } => {
+            let nickname = String::from(User::new(who).get_nickname());
+            runner.send(ChatEvent::ChannelMsg { channel: to.clone(), from: nickname.clone(), msg: msg.clone() }).unwrap()
+        },
+        Message { prefix: Some(ref who), command: Command::JOIN(ref channel, _, _), .. } => {
+            let nickname = String::from(User::new(who).get_nickname());
+            runner.send(ChatEvent::JoinedChannel { channel: channel.clone(), who: nickname }).unwrap()
+        },
+        Message { prefix: Some(ref who), command: Command::PART(ref channel, ref maybe_comment), .. } => {
+            let nickname = String::from(User::new(who).get_nickname());
+            runner.send(ChatEvent::PartedChannel { channel: channel.clone(), who: nickname, comment: maybe_comment.clone() }).unwrap()
+        },
+        Message { command: Command::Response(Response::RPL_ISUPPORT, ref args, _), .. } if !args.is_empty() => {
+            runner.send(ChatEvent::ServerSupport { tokens: args[1..].to_vec() }).unwrap()
+        },
+        Message { command: Command::Response(Response::RPL_NAMREPLY, ref args, Some(ref names)), .. } if !args.is_empty() => {
+            let channel = args[args.len() - 1].clone();
+            let names = names.split_whitespace().map(String::from).collect();
+            runner.send(ChatEvent::ChannelNames { channel: channel, names: names }).unwrap()
+        },
+        Message { command: Command::Response(Response::RPL_ENDOFNAMES, ref args, _), .. } if !args.is_empty() => {
+            runner.send(ChatEvent::ChannelNamesEnd { channel: args[args.len() - 1].clone() }).unwrap()
+        },
+        Message { prefix: Some(ref who), command: Command::MODE(ref channel, ref modes, ref maybe_args), .. } if channel.starts_with("#") || channel.starts_with("&") => {
+            let nickname = String::from(User::new(who).get_nickname());
+            let args = maybe_args.as_ref().map_or(Vec::new(), |a| a.split_whitespace().map(String::from).collect());
+            runner.send(ChatEvent::ChannelMode { channel: channel.clone(), by: nickname, modes: modes.clone(), args: args }).unwrap()
+        },
+        Message { prefix: Some(ref who), command: Command::NICK(ref new), .. } => {
+            let old = String::from(User::new(who).get_nickname());
+            runner.send(ChatEvent::NickChanged { old: old, new: new.clone() }).unwrap()
+        },
+        Message { prefix: Some(ref who), command: Command::QUIT(ref maybe_reason), .. } => {
+            let nickname = String::from(User::new(who).get_nickname());
+            runner.send(ChatEvent::Quit { who: nickname, reason: maybe_reason.clone() }).unwrap()
+        },
+        Message { prefix: Some(ref who), command: Command::KICK(ref channels, ref users, ref maybe_reason), .. } => {
+            let by = String::from(User::new(who).get_nickname());
+            let channels: Vec<&str> = channels.split(',').collect();
+            for (i, user) in users.split(',').enumerate() {
+                let channel = channels.get(i).unwrap_or(&channels[0]);
+                runner.send(ChatEvent::Kicked { channel: (*channel).to_owned(), who: user.to_owned(), by: by.clone(), reason: maybe_reason.clone() }).unwrap()
+            }
+        },
+        Message { prefix: Some(ref who), command: Command::TOPIC(ref channel, Some(ref topic)), .. } => {
+            let nickname = String::from(User::new(who).get_nickname());
+            runner.send(ChatEvent::TopicChanged { channel: channel.clone(), by: nickname, topic: topic.clone() }).unwrap()
+        },
+        Message { prefix: Some(ref who), command: Command::NOTICE(ref to, ref msg), .. } => {
+            let nickname = String::from(User::new(who).get_nickname());
+            runner.send(ChatEvent::Notice { from: nickname, to: to.clone(), msg: msg.clone() }).unwrap()
+        },
+        Message { command: Command::PONG(..), .. } => (),
+        message => print!("Unhandled: {}", message),
+    }
+}
+
+pub fn run<F, S: Send + 'static>(config: &str, limits: Limits, f: F, s: S) where F: Fn(Event<ChatEvent>, &mut S) -> Option<Effect<ChatEffect, ()>> + Send + 'static  {
+    let current: Arc<Mutex<Option<IrcServer>>> = Arc::new(Mutex::new(None));
+
+    let outbox = Arc::new((Mutex::new(Outbox::new(limits)), Condvar::new()));
+    spawn_sender(outbox.clone(), current.clone());
+
+    let prefix_len = Arc::new(AtomicUsize::new(DEFAULT_PREFIX_LEN));
+    let prefix_len_for_effects = prefix_len.clone();
+    let handle_chat_effect = move |eff| {
+        let (ref lock, ref wakeup) = *outbox;
+        queue_chat_effect(&mut lock.lock().unwrap(), eff, Instant::now(), prefix_len_for_effects.load(Ordering::Relaxed));
+        wakeup.notify_one();
+        noop()
+    };
+
+    let mut runner = Runner::new(f, handle_chat_effect, s);
+    runner.heartbeats(Duration::from_secs(60));
+
+    let (tx, rx) = channel();
+    let mut backoff = Backoff::new(Duration::from_millis(BACKOFF_INITIAL_MS), Duration::from_millis(BACKOFF_MAX_MS));
+    let mut generation = 0;
+    loop {
+        match connect(config) {
+            Ok(server) => {
+                generation += 1;
+                *current.lock().unwrap() = Some(server.clone());
+                spawn_reader(generation, server.clone(), tx.clone());
+                prefix_len.store(estimated_prefix_len(server.current_nickname()), Ordering::Relaxed);
+                let closed = supervise(generation, &server, &rx, &runner, &mut backoff, &prefix_len);
+                *current.lock().unwrap() = None;
+                if !closed && !retire_reader(generation, &rx, Duration::from_secs(READER_SHUTDOWN_SECS)) {
+                    println!("Connection {} did not close, abandoning its reader", generation)
+                }
+                runner.send(ChatEvent::Disconnected).unwrap();
             },
-            Ok(message) => print!("Unhandled: {}", message),
-            Err(err) => println!("{}", err),
+            Err(err) => println!("Unable to connect: {}", err),
         }
+        let delay = backoff.next_delay();
+        println!("Reconnecting in {:?}", delay);
+        thread::sleep(delay);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use irc::*;
+    use std::iter::repeat;
+    use std::sync::mpsc::channel;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn ctcp_action_test() {
+        assert_eq!(ctcp_action("\u{1}ACTION waves #hello\u{1}"), Some("waves #hello"));
+        assert_eq!(ctcp_action("\u{1}ACTION waves"), Some("waves"));
+        assert_eq!(ctcp_action("\u{1}VERSION\u{1}"), None);
+        assert_eq!(ctcp_action("ACTION waves"), None);
+    }
+
+    #[test]
+    fn line_budget_test() {
+        assert_eq!(line_budget(20, "#chan"), 512 - ":".len() - 20 - " PRIVMSG #chan :\r\n".len());
+        assert_eq!(line_budget(600, "#chan"), 0);
+    }
+
+    #[test]
+    fn keep_short_lines_test() {
+        assert_eq!(split_line("hello world", 100), vec!["hello world".to_owned()]);
+        assert_eq!(split_line("", 100), vec!["".to_owned()]);
+    }
+
+    #[test]
+    fn split_between_words_test() {
+        let line = "the quick brown fox jumps over the lazy dog and keeps running far away";
+        assert_eq!(split_line(line, 40), vec![
+            "the quick brown fox jumps over the ...".to_owned(),
+            "... lazy dog and keeps running far away".to_owned()]);
+    }
+
+    #[test]
+    fn split_long_words_test() {
+        let xs = |n| repeat("x").take(n).collect::<String>();
+        let pieces = split_line(&xs(100), 40);
+        assert_eq!(pieces, vec![format!("{} ...", xs(36)), format!("... {} ...", xs(32)), format!("... {}", xs(32))]);
+    }
+
+    #[test]
+    fn stay_within_tiny_budgets_test() {
+        let xs = repeat("x").take(25).collect::<String>();
+        assert_eq!(split_line(&xs, 10), vec![xs[..10].to_owned(), xs[10..20].to_owned(), xs[20..].to_owned()]);
+        assert_eq!(split_line("åäö", 3), vec!["å".to_owned(), "ä".to_owned(), "ö".to_owned()]);
+    }
+
+    #[test]
+    fn split_on_char_boundaries_test() {
+        let line = repeat("åäö").take(40).collect::<String>();
+        let pieces = split_line(&line, 41);
+        assert!(pieces.len() > 1);
+        for piece in pieces.iter() {
+            assert!(piece.len() <= 41);
+        }
+        let joined: String = pieces.iter().map(|p| p.trim_left_matches("... ").trim_right_matches(" ...")).collect();
+        assert_eq!(joined, line);
+    }
+
+    #[test]
+    fn fit_every_piece_in_budget_test() {
+        let line = (0..200).map(|i| format!("word{}", i)).collect::<Vec<String>>().join(" ");
+        let budget = line_budget(60, "#channel");
+        let pieces = split_line(&line, budget);
+        assert!(pieces.len() > 1);
+        for piece in pieces.iter() {
+            assert!(piece.len() <= budget);
+        }
+        let words: Vec<&str> = pieces.iter().flat_map(|p| p.split(' ')).filter(|w| *w != "...").collect();
+        assert_eq!(words.join(" "), line);
+    }
+
+    #[test]
+    fn back_off_exponentially_up_to_max_test() {
+        let mut backoff = Backoff::new(Duration::from_millis(1000), Duration::from_millis(10000));
+        let delays = (0..6).map(|_| backoff.next_delay_with(0.999999)).map(|d| d.as_secs()).collect::<Vec<u64>>();
+        assert_eq!(delays, vec![0, 1, 3, 7, 9, 9]);
+    }
+
+    #[test]
+    fn jitter_within_upper_half_of_step_test() {
+        let mut low = Backoff::new(Duration::from_millis(1000), Duration::from_millis(10000));
+        let mut high = Backoff::new(Duration::from_millis(1000), Duration::from_millis(10000));
+        low.next_delay_with(0.0);
+        high.next_delay_with(0.0);
+        assert_eq!(low.next_delay_with(0.0), Duration::from_millis(1000));
+        assert_eq!(high.next_delay_with(0.5), Duration::from_millis(1500));
+        for _ in 0..100 {
+            let d = low.next_delay();
+            assert!(d >= Duration::from_millis(500) && d <= Duration::from_millis(10000));
+        }
+    }
+
+    #[test]
+    fn reset_backoff_test() {
+        let mut backoff = Backoff::new(Duration::from_millis(1000), Duration::from_millis(10000));
+        for _ in 0..5 {
+            backoff.next_delay_with(0.0);
+        }
+        backoff.reset();
+        assert_eq!(backoff.next_delay_with(0.0), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn watchdog_pings_then_gives_up_test() {
+        let start = Instant::now();
+        let mut watchdog = Watchdog::new(start, Duration::from_secs(60), Duration::from_secs(180));
+        assert_eq!(watchdog.check(start + Duration::from_secs(30)), Liveness::Alive);
+        assert_eq!(watchdog.check(start + Duration::from_secs(60)), Liveness::Ping);
+        assert_eq!(watchdog.check(start + Duration::from_secs(90)), Liveness::Alive);
+        assert_eq!(watchdog.check(start + Duration::from_secs(180)), Liveness::Dead);
+    }
+
+    #[test]
+    fn watchdog_rearms_when_server_is_heard_test() {
+        let start = Instant::now();
+        let mut watchdog = Watchdog::new(start, Duration::from_secs(60), Duration::from_secs(180));
+        assert_eq!(watchdog.check(start + Duration::from_secs(60)), Liveness::Ping);
+        watchdog.seen(start + Duration::from_secs(70));
+        assert_eq!(watchdog.check(start + Duration::from_secs(100)), Liveness::Alive);
+        assert_eq!(watchdog.check(start + Duration::from_secs(130)), Liveness::Ping);
+    }
+
+    #[test]
+    fn retire_reader_once_its_connection_closes_test() {
+        let (tx, rx) = channel();
+        tx.send(Incoming::Closed(1)).unwrap();
+        tx.send(Incoming::Closed(2)).unwrap();
+        assert!(retire_reader(2, &rx, Duration::from_secs(1)));
+        assert!(!retire_reader(3, &rx, Duration::from_millis(10)));
     }
 }
@@ -1,3 +1,5 @@
 pub mod irc;
 pub mod db;
 pub mod free_runner;
+pub mod outbox;
+pub mod channel_state;
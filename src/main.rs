@@ -4,8 +4,16 @@ extern crate lazy_static;
 extern crate rootmos_bot;
 use rootmos_bot::free_runner::*;
 use rootmos_bot::irc::*;
+use rootmos_bot::outbox::Limits;
+use rootmos_bot::channel_state::ChannelState;
 use rootmos_bot::db;
 use rootmos_bot::db::KV;
+use rootmos_bot::db::typed;
+use rootmos_bot::db::typed::Store;
+use rootmos_bot::db::typed::Key;
+use rootmos_bot::db::key::Tuple;
+use rootmos_bot::db::migrate;
+use rootmos_bot::db::migrate::Migration;
 
 extern crate chrono;
 use chrono::*;
@@ -23,34 +31,314 @@ extern crate serde_json;
 include!(concat!(env!("OUT_DIR"), "/serde_types.rs"));
 
 use std::path::Path;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::fmt;
+use std::thread;
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
 
-fn tag_bot<KV>(event: Event<ChatEvent>, kv: &mut KV) -> Option<Effect<ChatEffect, ()>> where KV: db::KV<String, String> {
+const SEARCH_MAX_RESULTS: usize = 10;
+
+fn default_page_size() -> usize { 10 }
+fn default_trash_max_age_hours() -> i64 { 24 * 7 }
+fn default_recent_lines() -> usize { 100 }
+fn default_snapshot_interval_hours() -> i64 { 24 }
+fn default_snapshot_keep() -> usize { 7 }
+fn default_db_backend() -> String { "rocksdb".to_owned() }
+fn default_flood_burst() -> u32 { Limits::default().burst }
+fn default_flood_rate_ms() -> u64 { Limits::default().rate.as_secs() * 1000 }
+fn default_flood_max_queue() -> usize { Limits::default().max_queue }
+fn default_flood_max_age_secs() -> u64 { Limits::default().max_age.as_secs() }
+fn default_flood_short_reply_lines() -> usize { Limits::default().short_reply_lines }
+
+impl Default for Config {
+    fn default() -> Config {
+        Config { page_size: default_page_size(), newest_first: false, private_threshold: None, admins: Vec::new(),
+            trash_max_age_hours: default_trash_max_age_hours(), recent_lines: default_recent_lines(),
+            snapshot_dir: None, snapshot_interval_hours: default_snapshot_interval_hours(), snapshot_keep: default_snapshot_keep(),
+            db_backend: default_db_backend(), db_path: None,
+            flood_burst: default_flood_burst(), flood_rate_ms: default_flood_rate_ms(), flood_max_queue: default_flood_max_queue(),
+            flood_max_age_secs: default_flood_max_age_secs(), flood_short_reply_lines: default_flood_short_reply_lines() }
+    }
+}
+
+impl Config {
+    fn load(path: &Path) -> Config {
+        let config: Config = if path.exists() {
+            serde_json::from_reader(File::open(path).unwrap()).unwrap()
+        } else {
+            Config::default()
+        };
+        if let Err(err) = config.check() {
+            panic!("Invalid {}: {}", path.display(), err)
+        }
+        config
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if self.page_size == 0 {
+            return Err("page_size must be at least 1".to_owned())
+        }
+        Ok(())
+    }
+
+    fn flood_limits(&self) -> Limits {
+        Limits {
+            burst: self.flood_burst,
+            rate: std::time::Duration::from_millis(self.flood_rate_ms),
+            max_queue: self.flood_max_queue,
+            max_age: std::time::Duration::from_secs(self.flood_max_age_secs),
+            short_reply_lines: self.flood_short_reply_lines,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct RecentLine {
+    from: String,
+    msg: String,
+    time: DateTime<UTC>,
+}
+
+struct TagBot<KV> {
+    store: Store<KV>,
+    config: Config,
+    channels: ChannelState,
+    recent: HashMap<String, VecDeque<RecentLine>>,
+    snapshots: Snapshots,
+}
+
+impl <KV> TagBot<KV> where KV: db::KV<String, String> {
+    fn new(kv: KV, config: Config) -> TagBot<KV> {
+        TagBot { store: Store::new(kv), config: config, channels: ChannelState::new(), recent: HashMap::new(), snapshots: Snapshots::default() }
+    }
+
+    fn remember(&mut self, channel: &String, from: &String, msg: &String, time: DateTime<UTC>) {
+        let lines = self.recent.entry(channel.clone()).or_insert_with(VecDeque::new);
+        lines.push_back(RecentLine { from: from.clone(), msg: msg.clone(), time: time });
+        while lines.len() > self.config.recent_lines {
+            lines.pop_front();
+        }
+    }
+
+    fn is_privileged(&self, channel: &String, nick: &String) -> bool {
+        self.config.admins.contains(nick) || self.channels.has_status(channel, nick)
+    }
+}
+
+#[derive(Debug)]
+enum BotError {
+    Storage(db::Error),
+    Io(io::Error),
+}
+
+impl From<db::Error> for BotError {
+    fn from(err: db::Error) -> BotError { BotError::Storage(err) }
+}
+
+impl From<io::Error> for BotError {
+    fn from(err: io::Error) -> BotError { BotError::Io(err) }
+}
+
+impl fmt::Display for BotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BotError::Storage(db::Error::Encoding(_)) => write!(f, "unreadable record"),
+            BotError::Storage(_) => write!(f, "storage error"),
+            BotError::Io(_) => write!(f, "I/O error"),
+        }
+    }
+}
+
+fn respond(channel: String, result: Result<ChatEffect, BotError>) -> ChatEffect {
+    match result {
+        Ok(eff) => eff,
+        Err(err) => {
+            println!("Error: {:?}", err);
+            let msg = vec![format!("Sorry, that failed: {}", err)];
+            ChatEffect::ChannelMsg { channel: channel, msg: msg }
+        },
+    }
+}
+
+fn tag_bot<KV>(event: Event<ChatEvent>, bot: &mut TagBot<KV>) -> Option<Effect<ChatEffect, ()>> where KV: db::KV<String, String> {
     println!("Event: {:?}", event);
-    lazy_static! {
-        static ref LIST_CMD: Regex = Regex::new(r"^!list\s+(#[a-zA-Z0-9]+)$").unwrap();
-        static ref UNTAG_CMD: Regex = Regex::new(r"^!untag\s+(#[a-zA-Z0-9]+)\s+([a-fA-F0-9]+)$").unwrap();
-        static ref TAGGED: Regex = Regex::new(r"(\s|^)(#[a-zA-Z0-9]+)(\s|$)").unwrap();
+    if let Event::Event { event: ref ev, .. } = event {
+        bot.channels.update(ev)
     }
 
     match event {
+        Event::Heartbeat { time } => {
+            if let Err(err) = purge_trash(time, &bot.config, &mut bot.store) {
+                println!("Error: unable to purge trash: {:?}", err)
+            }
+            if let Err(err) = snapshot(time, &bot.config, &mut bot.snapshots, &bot.store) {
+                println!("Error: unable to snapshot tag_bot_db: {:?}", err)
+            }
+            noop()
+        },
         Event::Event { time, event: ChatEvent::ChannelMsg { channel, msg, from } } =>
-            if let Some(cap) = LIST_CMD.captures(msg.as_str()) {
-                let tag = cap.at(1).unwrap();
-                effect(list_cmd(channel, tag.to_owned(), kv))
-            } else if let Some(cap) = UNTAG_CMD.captures(msg.as_str()) {
-                let tag = cap.at(1).unwrap();
-                let hash = cap.at(2).unwrap();
-                effect(untag_cmd(channel, tag.to_owned(), hash.to_owned(), kv))
-            } else if let Some(cap) = TAGGED.captures(msg.as_str()) {
-                let tag = cap.at(2).unwrap();
-                effect(tag_line(time, from, channel, tag.to_owned(), msg.clone(), kv))
-            } else {
-                noop()
-            },
+            channel_cmd(time, channel.clone(), from, msg, bot).map(|result| Effect::Effect(respond(channel, result))),
+        Event::Event { time, event: ChatEvent::ChannelAction { channel, msg, from } } =>
+            channel_action(time, channel.clone(), from, msg, bot).map(|result| Effect::Effect(respond(channel, result))),
+        Event::Event { time, event: ChatEvent::PrivateMsg { from, msg } } =>
+            private_cmd(time, from.clone(), msg, bot).map(|result| Effect::Effect(privately(&from, respond(from.clone(), result)))),
         _  => noop(),
     }
 }
 
+fn channel_cmd<KV>(time: DateTime<UTC>, channel: String, from: String, msg: String, bot: &mut TagBot<KV>) -> Option<Result<ChatEffect, BotError>> where KV: db::KV<String, String> {
+    lazy_static! {
+        static ref LIST_CMD: Regex = Regex::new(r"^!list\s+(#[a-zA-Z0-9]+)(\s+([0-9]+))?$").unwrap();
+        static ref UNTAG_CMD: Regex = Regex::new(r"^!untag\s+(#[a-zA-Z0-9]+)\s+([a-fA-F0-9]+)$").unwrap();
+        static ref SEARCH_CMD: Regex = Regex::new(r"^!search\s+(.+)$").unwrap();
+        static ref TAGS_CMD: Regex = Regex::new(r"^!tags(\s+(recent|count))?$").unwrap();
+        static ref UNDO_CMD: Regex = Regex::new(r"^!undo$").unwrap();
+        static ref TAG_CMD: Regex = Regex::new(r"^!tag\s+(#[a-zA-Z0-9]+)(\s+(.+))?$").unwrap();
+        static ref RESTORE_CMD: Regex = Regex::new(r"^!restore\s+(#[a-zA-Z0-9]+)\s+([a-fA-F0-9]+)$").unwrap();
+    }
+
+    if let Some(cap) = LIST_CMD.captures(msg.as_str()) {
+        let tag = cap.at(1).unwrap();
+        let page = cap.at(3).map_or(1, |p| p.parse().unwrap_or(1));
+        Some(list_cmd(channel, from, tag.to_owned(), page, Reply::InChannel, &bot.config, &bot.store))
+    } else if let Some(cap) = UNTAG_CMD.captures(msg.as_str()) {
+        let tag = cap.at(1).unwrap();
+        let hash = cap.at(2).unwrap();
+        let privileged = bot.is_privileged(&channel, &from);
+        Some(untag_cmd(time, channel, from, tag.to_owned(), hash.to_owned(), privileged, Reply::InChannel, &mut bot.store))
+    } else if UNDO_CMD.is_match(msg.as_str()) {
+        Some(undo_cmd(channel, from, &mut bot.store))
+    } else if let Some(cap) = RESTORE_CMD.captures(msg.as_str()) {
+        let tag = cap.at(1).unwrap();
+        let hash = cap.at(2).unwrap();
+        let privileged = bot.is_privileged(&channel, &from);
+        Some(restore_cmd(channel, from, tag.to_owned(), hash.to_owned(), privileged, &mut bot.store))
+    } else if let Some(cap) = SEARCH_CMD.captures(msg.as_str()) {
+        let query = cap.at(1).unwrap();
+        Some(search_cmd(channel, query.to_owned(), &bot.store))
+    } else if let Some(cap) = TAGS_CMD.captures(msg.as_str()) {
+        let by_count = cap.at(2) == Some("count");
+        Some(tags_cmd(channel, by_count, &bot.store))
+    } else if let Some(cap) = TAG_CMD.captures(msg.as_str()) {
+        let tag = cap.at(1).unwrap();
+        let arg = cap.at(3).map(|a| a.trim().to_owned());
+        Some(tag_cmd(time, channel, from, tag.to_owned(), arg, &bot.recent, &mut bot.store))
+    } else {
+        if !msg.starts_with("!") {
+            bot.remember(&channel, &from, &msg, time);
+        }
+        let tags = find_tags(msg.as_str());
+        if tags.is_empty() {
+            None
+        } else {
+            Some(tag_line(time, from, channel, tags, msg.clone(), &mut bot.store))
+        }
+    }
+}
+
+fn channel_action<KV>(time: DateTime<UTC>, channel: String, from: String, action: String, bot: &mut TagBot<KV>) -> Option<Result<ChatEffect, BotError>> where KV: db::KV<String, String> {
+    let line = format!("* {} {}", from, action);
+    bot.remember(&channel, &from, &line, time);
+    let tags = find_tags(action.as_str());
+    if tags.is_empty() {
+        None
+    } else {
+        Some(tag_line(time, from, channel, tags, line, &mut bot.store))
+    }
+}
+
+fn private_cmd<KV>(time: DateTime<UTC>, from: String, msg: String, bot: &mut TagBot<KV>) -> Option<Result<ChatEffect, BotError>> where KV: db::KV<String, String> {
+    lazy_static! {
+        static ref LIST_CMD: Regex = Regex::new(r"^!list\s+([#&][^\s,]+)\s+(#[a-zA-Z0-9]+)(\s+([0-9]+))?$").unwrap();
+        static ref UNTAG_CMD: Regex = Regex::new(r"^!untag\s+([#&][^\s,]+)\s+(#[a-zA-Z0-9]+)\s+([a-fA-F0-9]+)$").unwrap();
+        static ref SEARCH_CMD: Regex = Regex::new(r"^!search\s+([#&][^\s,]+)\s+(.+)$").unwrap();
+        static ref TAGS_CMD: Regex = Regex::new(r"^!tags\s+([#&][^\s,]+)(\s+(recent|count))?$").unwrap();
+        static ref UNDO_CMD: Regex = Regex::new(r"^!undo\s+([#&][^\s,]+)$").unwrap();
+        static ref RESTORE_CMD: Regex = Regex::new(r"^!restore\s+([#&][^\s,]+)\s+(#[a-zA-Z0-9]+)\s+([a-fA-F0-9]+)$").unwrap();
+        static ref CHANNEL_ARG: Regex = Regex::new(r"^!(list|untag|undo|restore|search|tags)\s+([#&][^\s,]+)").unwrap();
+    }
+
+    if let Some(cap) = CHANNEL_ARG.captures(msg.as_str()) {
+        let channel = cap.at(2).unwrap().to_owned();
+        if !bot.config.admins.contains(&from) && !bot.channels.is_member(&channel, &from) {
+            let refusal = vec![format!("You need to be in {} to do that", channel)];
+            return Some(Ok(ChatEffect::PrivateMsg { to: from, msg: refusal }))
+        }
+    }
+
+    if let Some(cap) = LIST_CMD.captures(msg.as_str()) {
+        let channel = cap.at(1).unwrap();
+        let tag = cap.at(2).unwrap();
+        let page = cap.at(4).map_or(1, |p| p.parse().unwrap_or(1));
+        let config = Config { private_threshold: None, .. bot.config.clone() };
+        Some(list_cmd(channel.to_owned(), from, tag.to_owned(), page, Reply::InPrivate, &config, &bot.store))
+    } else if let Some(cap) = UNTAG_CMD.captures(msg.as_str()) {
+        let channel = cap.at(1).unwrap();
+        let tag = cap.at(2).unwrap();
+        let hash = cap.at(3).unwrap();
+        let privileged = bot.is_privileged(&channel.to_owned(), &from);
+        Some(untag_cmd(time, channel.to_owned(), from, tag.to_owned(), hash.to_owned(), privileged, Reply::InPrivate, &mut bot.store))
+    } else if let Some(cap) = UNDO_CMD.captures(msg.as_str()) {
+        let channel = cap.at(1).unwrap();
+        Some(undo_cmd(channel.to_owned(), from, &mut bot.store))
+    } else if let Some(cap) = RESTORE_CMD.captures(msg.as_str()) {
+        let channel = cap.at(1).unwrap();
+        let tag = cap.at(2).unwrap();
+        let hash = cap.at(3).unwrap();
+        let privileged = bot.is_privileged(&channel.to_owned(), &from);
+        Some(restore_cmd(channel.to_owned(), from, tag.to_owned(), hash.to_owned(), privileged, &mut bot.store))
+    } else if let Some(cap) = SEARCH_CMD.captures(msg.as_str()) {
+        let channel = cap.at(1).unwrap();
+        let query = cap.at(2).unwrap();
+        Some(search_cmd(channel.to_owned(), query.to_owned(), &bot.store))
+    } else if let Some(cap) = TAGS_CMD.captures(msg.as_str()) {
+        let channel = cap.at(1).unwrap();
+        let by_count = cap.at(3) == Some("count");
+        Some(tags_cmd(channel.to_owned(), by_count, &bot.store))
+    } else {
+        None
+    }
+}
+
+fn privately(to: &String, eff: ChatEffect) -> ChatEffect {
+    match eff {
+        ChatEffect::ChannelMsg { msg, .. } => ChatEffect::PrivateMsg { to: to.clone(), msg: msg },
+        ChatEffect::PrivateMsg { msg, .. } => ChatEffect::PrivateMsg { to: to.clone(), msg: msg },
+        ChatEffect::Many(effs) => ChatEffect::Many(effs.into_iter().map(|eff| privately(to, eff)).collect()),
+    }
+}
+
+fn find_tags(msg: &str) -> Vec<String> {
+    lazy_static! {
+        static ref TAG: Regex = Regex::new(r"^#[a-zA-Z0-9]+$").unwrap();
+    }
+
+    let mut tags: Vec<String> = Vec::new();
+    for word in msg.split(|c: char| c.is_whitespace() || c == ',' || c == ';') {
+        let word = word.trim_right_matches(|c: char| c == '.' || c == ':' || c == '!' || c == '?');
+        if TAG.is_match(word) && !tags.iter().any(|t| t == word) {
+            tags.push(word.to_owned())
+        }
+    }
+    tags
+}
+
+fn words(s: &str) -> Vec<String> {
+    let mut words: Vec<String> = Vec::new();
+    for word in s.split(|c: char| !c.is_alphanumeric()) {
+        let word = word.to_lowercase();
+        if !word.is_empty() && !words.contains(&word) {
+            words.push(word)
+        }
+    }
+    words
+}
+
 fn hash(s: &String) -> String {
     let mut hasher = Sha256::new();
     hasher.input_str(s.as_str());
@@ -60,290 +348,2182 @@ fn hash(s: &String) -> String {
 }
 
 fn mk_key(channel: &String, tag: &String, hash: &String) -> String {
-    format!("{}{}", mk_key_prefix(channel, tag), hash)
+    Tuple::new().push("line").push(channel).push(tag).push(hash).encode()
 }
 
 fn mk_key_prefix(channel: &String, tag: &String) -> String {
-    format!("{}-{}-", channel, tag)
+    Tuple::new().push("line").push(channel).push(tag).encode()
+}
+
+fn mk_channel_prefix(channel: &String) -> String {
+    Tuple::new().push("line").push(channel).encode()
+}
+
+fn mk_lines_prefix() -> String {
+    Tuple::new().push("line").encode()
+}
+
+fn mk_trash_key(channel: &String, tag: &String, hash: &String) -> String {
+    Tuple::new().push("trash").push(channel).push(tag).push(hash).encode()
+}
+
+fn mk_trash_channel_prefix(channel: &String) -> String {
+    Tuple::new().push("trash").push(channel).encode()
+}
+
+fn mk_trash_prefix() -> String {
+    Tuple::new().push("trash").encode()
+}
+
+fn mk_time_key(channel: &String, tag: &String, time: &DateTime<UTC>, hash: &String) -> String {
+    let ts = format!("{:012}.{:09}", time.timestamp(), time.nanosecond());
+    Tuple::new().push("time").push(channel).push(tag).push(&ts).push(hash).encode()
+}
+
+fn mk_time_prefix() -> String {
+    Tuple::new().push("time").encode()
+}
+
+fn mk_time_key_prefix(channel: &String, tag: &String) -> String {
+    Tuple::new().push("time").push(channel).push(tag).encode()
+}
+
+fn mk_search_key(channel: &String, word: &String, tag: &String, hash: &String) -> String {
+    Tuple::new().push("search").push(channel).push(word).push(tag).push(hash).encode()
+}
+
+fn mk_search_key_prefix(channel: &String, word: &String) -> String {
+    Tuple::new().push("search").push(channel).push(word).encode()
+}
+
+fn mk_count_key(channel: &String, tag: &String) -> String {
+    Tuple::new().push("count").push(channel).push(tag).encode()
+}
+
+fn mk_count_prefix() -> String {
+    Tuple::new().push("count").encode()
+}
+
+fn mk_meta_key(name: &str) -> String {
+    Tuple::new().push("meta").push(name).encode()
+}
+
+#[cfg(feature = "sqlite")]
+fn index_sqlite_line(key: &str, value: &str) -> Option<db::sqlite_kv::IndexedLine> {
+    if !key.starts_with(&mk_lines_prefix()) {
+        return None
+    }
+    typed::decode::<TaggedLine>(key, value).ok().map(|tl| db::sqlite_kv::IndexedLine {
+        channel: tl.channel,
+        tag: tl.tag,
+        user: tl.user,
+        time: tl.time.timestamp(),
+        line: tl.line })
+}
+
+fn index_line(tl: &TaggedLine, batch: &mut typed::Batch) {
+    let key = mk_key(&tl.channel, &tl.tag, &tl.hash);
+    batch.put_raw(&mk_time_key(&tl.channel, &tl.tag, &tl.time, &tl.hash), key.clone());
+    for word in words(tl.line.as_str()) {
+        batch.put_raw(&mk_search_key(&tl.channel, &word, &tl.tag, &tl.hash), key.clone());
+    }
+}
+
+fn unindex_line(tl: &TaggedLine, batch: &mut typed::Batch) {
+    batch.delete(&mk_time_key(&tl.channel, &tl.tag, &tl.time, &tl.hash));
+    for word in words(tl.line.as_str()) {
+        batch.delete(&mk_search_key(&tl.channel, &word, &tl.tag, &tl.hash));
+    }
+}
+
+fn put_line<KV>(tl: &TaggedLine, store: &Store<KV>, batch: &mut typed::Batch) -> Result<bool, db::Error> where KV: db::KV<String, String> {
+    let key = mk_key(&tl.channel, &tl.tag, &tl.hash);
+    let existing = try!(store.kv().get(&key));
+    if let Some(ref json) = existing {
+        match typed::decode::<TaggedLine>(&key, json) {
+            Ok(old) => unindex_line(&old, batch),
+            Err(err) => println!("Replacing unreadable record: {}", err),
+        }
+    }
+    try!(batch.put(&key, tl));
+    index_line(tl, batch);
+    Ok(existing.is_none())
+}
+
+fn line_count<KV>(channel: &String, tag: &String, store: &Store<KV>) -> Result<usize, db::Error> where KV: db::KV<String, String> {
+    store.get::<usize>(&mk_count_key(channel, tag)).map(|count| count.unwrap_or(0))
+}
+
+fn adjust_line_count<KV>(channel: &String, tag: &String, delta: isize, store: &Store<KV>, batch: &mut typed::Batch) -> Result<(), db::Error> where KV: db::KV<String, String> {
+    let count = try!(line_count(channel, tag, store)) as isize + delta;
+    if count > 0 {
+        batch.put(&mk_count_key(channel, tag), &(count as usize))
+    } else {
+        batch.delete(&mk_count_key(channel, tag));
+        Ok(())
+    }
+}
+
+fn backfill_time_index<KV>(store: &mut Store<KV>) -> Result<usize, db::Error> where KV: db::KV<String, String> {
+    let marker = mk_meta_key("time-index");
+    if try!(store.kv().get(&marker)).is_some() {
+        return Ok(0)
+    }
+
+    let mut batch = typed::Batch::new();
+    for pair in store.kv().scan(db::Scan::prefix(mk_lines_prefix())) {
+        let (key, json) = try!(pair);
+        match typed::decode::<TaggedLine>(&key, &json) {
+            Ok(tl) => batch.put_raw(&mk_time_key(&tl.channel, &tl.tag, &tl.time, &tl.hash), key),
+            Err(err) => println!("Skipping unreadable record: {}", err),
+        }
+    }
+    let backfilled = batch.len();
+    batch.put_raw(&marker, UTC::now().to_rfc3339());
+    try!(store.write(batch));
+    Ok(backfilled)
+}
+
+fn migrate_keys<KV>(store: &mut Store<KV>) -> Result<usize, db::Error> where KV: db::KV<String, String> {
+    let mut batch = typed::Batch::new();
+    let mut migrated = 0;
+    for pair in store.kv().scan(db::Scan::all()) {
+        let (key, json) = try!(pair);
+        if key.contains('\u{0}') {
+            continue
+        }
+        if key.starts_with("time-") || key.starts_with("search-") {
+            batch.delete(&key);
+        } else if key == "meta-time-index" {
+            batch.delete(&key);
+            batch.put_raw(&mk_meta_key("time-index"), json);
+        } else if key.starts_with("trash-") {
+            match typed::decode::<TrashedLine>(&key, &json) {
+                Ok(trashed) => {
+                    batch.delete(&key);
+                    batch.put_raw(&mk_trash_key(&trashed.line.channel, &trashed.line.tag, &trashed.line.hash), json);
+                    migrated += 1
+                },
+                Err(err) => println!("Skipping unreadable record: {}", err),
+            }
+        } else {
+            match typed::decode::<TaggedLine>(&key, &json) {
+                Ok(tl) => {
+                    batch.delete(&key);
+                    batch.put_raw(&mk_key(&tl.channel, &tl.tag, &tl.hash), json);
+                    index_line(&tl, &mut batch);
+                    migrated += 1
+                },
+                Err(err) => println!("Skipping unreadable record: {}", err),
+            }
+        }
+    }
+    try!(store.write(batch));
+    Ok(migrated)
+}
+
+fn count_lines<KV>(store: &mut Store<KV>) -> Result<usize, db::Error> where KV: db::KV<String, String> {
+    let mut batch = typed::Batch::new();
+    for pair in store.kv().scan(db::Scan::prefix(mk_count_prefix())) {
+        let (key, _) = try!(pair);
+        batch.delete(&key);
+    }
+
+    let mut counts: HashMap<(String, String), usize> = HashMap::new();
+    for pair in store.kv().scan(db::Scan::prefix(mk_lines_prefix())) {
+        let (key, json) = try!(pair);
+        match typed::decode::<TaggedLine>(&key, &json) {
+            Ok(tl) => *counts.entry((tl.channel, tl.tag)).or_insert(0) += 1,
+            Err(err) => println!("Skipping unreadable record: {}", err),
+        }
+    }
+
+    let mut changed = 0;
+    for pair in store.kv().scan(db::Scan::prefix(mk_time_prefix())) {
+        let (time_key, key) = try!(pair);
+        let current = match store.get::<TaggedLine>(&key) {
+            Ok(Some(tl)) => mk_time_key(&tl.channel, &tl.tag, &tl.time, &tl.hash) == time_key,
+            Ok(None) => false,
+            Err(db::Error::Encoding(_)) => true,
+            Err(err) => return Err(err),
+        };
+        if !current {
+            batch.delete(&time_key);
+            changed += 1
+        }
+    }
+
+    for ((channel, tag), count) in counts {
+        try!(batch.put(&mk_count_key(&channel, &tag), &count));
+        changed += 1
+    }
+    try!(store.write(batch));
+    Ok(changed)
+}
+
+fn migrations<KV>() -> Vec<Migration<KV>> where KV: db::KV<String, String> {
+    vec![
+        Migration { version: 1, name: "tuple keys", run: migrate_keys::<KV> },
+        Migration { version: 2, name: "time index", run: backfill_time_index::<KV> },
+        Migration { version: 3, name: "line counts", run: count_lines::<KV> },
+    ]
+}
+
+fn report_migrations(applied: &Vec<migrate::Applied>) {
+    for a in applied {
+        let _ = writeln!(io::stderr(), "Migration {} ({}): {} records", a.version, a.name, a.records);
+    }
+}
+
+fn collect_records<I, T>(records: I) -> Result<(Vec<(String, T)>, usize), BotError> where I: Iterator<Item=Result<(String, T), db::Error>> {
+    let mut collected = Vec::new();
+    let mut skipped = 0;
+    for record in records {
+        match record {
+            Ok(pair) => collected.push(pair),
+            Err(db::Error::Encoding(err)) => {
+                println!("Skipping unreadable record: {}", err);
+                skipped += 1
+            },
+            Err(err) => return Err(BotError::from(err)),
+        }
+    }
+    Ok((collected, skipped))
+}
+
+fn report_skipped(skipped: usize, msg: &mut Vec<String>) {
+    if skipped > 0 {
+        msg.push(format!("Skipped {} unreadable records", skipped))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Reply {
+    InChannel,
+    InPrivate,
+}
+
+impl Reply {
+    // A command hinted at in a private reply has to name its channel.
+    fn cmd(self, name: &str, channel: &str, args: String) -> String {
+        match self {
+            Reply::InChannel => format!("!{} {}", name, args),
+            Reply::InPrivate => format!("!{} {} {}", name, channel, args),
+        }
+    }
+}
+
+fn format_tagged_line(l: &TaggedLine, reply: Reply) -> String {
+    format!(
+        "{} (by: {}, at: {}, untag: \"{}\")",
+        &l.line,
+        &l.user,
+        &l.time.with_timezone(&Local).to_rfc2822(),
+        reply.cmd("untag", &l.channel, format!("{} {}", l.tag, l.hash)))
+}
+
+fn list_cmd<KV>(channel: String, from: String, tag: String, page: usize, reply: Reply, config: &Config, store: &Store<KV>) -> Result<ChatEffect, BotError> where KV: db::KV<String, String> {
+
+    let time_prefix = mk_time_key_prefix(&channel, &tag);
+    let total = try!(line_count(&channel, &tag, store));
+
+    let private = config.private_threshold.map_or(false, |threshold| total > threshold);
+    let page = if page == 0 { 1 } else { page };
+    let start = match (page - 1).checked_mul(config.page_size) {
+        Some(start) if start < total || page == 1 => start,
+        _ => {
+            let error = format!("No page {} for tag {}, it has {} lines", page, tag, total);
+            return Ok(ChatEffect::ChannelMsg { channel: channel, msg: vec![error] })
+        },
+    };
+
+    let mut scan = db::Scan::prefix(time_prefix).limit(start.saturating_add(config.page_size));
+    if config.newest_first {
+        scan = scan.reverse();
+    }
+    let mut tagged_lines = Vec::new();
+    let mut skipped = 0;
+    for entry in store.kv().scan(scan).skip(start) {
+        let (_, key) = try!(entry);
+        match store.get::<TaggedLine>(&key) {
+            Ok(Some(tl)) => tagged_lines.push(tl),
+            Ok(None) => println!("Skipping dangling index entry for {}", key),
+            Err(db::Error::Encoding(err)) => {
+                println!("Skipping unreadable record: {}", err);
+                skipped += 1
+            },
+            Err(err) => return Err(BotError::from(err)),
+        }
+    }
+
+    let end = std::cmp::min(start.saturating_add(config.page_size), total);
+    if private {
+        let mut msg = tagged_lines.iter().map(|l| format_tagged_line(l, Reply::InChannel)).collect::<Vec<String>>();
+        msg.insert(0, format!("Listing tag {} in {}:", tag, channel));
+        if end < total {
+            msg.push(format!("Showing {}-{} of {}, use \"!list {} {}\" in {} for more", start + 1, end, total, tag, page + 1, channel));
+        }
+        report_skipped(skipped, &mut msg);
+        let notice = vec![format!("{}: sent you {} of {} lines", from, tagged_lines.len(), total)];
+        return Ok(ChatEffect::Many(vec![
+            ChatEffect::PrivateMsg { to: from, msg: msg },
+            ChatEffect::ChannelMsg { channel: channel, msg: notice }]))
+    }
+
+    let mut msg = tagged_lines.iter().map(|l| format_tagged_line(l, reply)).collect::<Vec<String>>();
+    msg.insert(0, format!("Listing tag {}:", tag));
+    if total > config.page_size {
+        if end < total {
+            let next = reply.cmd("list", &channel, format!("{} {}", tag, page + 1));
+            msg.push(format!("Showing {}-{} of {}, use \"{}\" for more", start + 1, end, total, next));
+        } else {
+            msg.push(format!("Showing {}-{} of {}", start + 1, end, total));
+        }
+    }
+    report_skipped(skipped, &mut msg);
+    Ok(ChatEffect::ChannelMsg { channel: channel, msg: msg })
+}
+
+fn tag_line<KV>(time: DateTime<UTC>, user: String, channel: String, tags: Vec<String>, line: String, store: &mut Store<KV>) -> Result<ChatEffect, BotError> where KV: db::KV<String, String> {
+    let line_hash = hash(&line);
+    let mut response = Vec::new();
+    let mut batch = typed::Batch::new();
+    for tag in tags {
+        let tagged_line = TaggedLine {
+            channel: channel.clone(),
+            tag: tag.clone(),
+            time: time,
+            user: user.clone(),
+            line: line.clone(),
+            hash: line_hash.clone()};
+        if try!(put_line(&tagged_line, store, &mut batch)) {
+            try!(adjust_line_count(&channel, &tag, 1, store, &mut batch));
+        }
+        response.push(format!("Line tagged, recall using: \"!list {}\", untag using \"!untag {} {}\"", tag, tag, line_hash));
+    }
+    try!(store.write(batch));
+    Ok(ChatEffect::ChannelMsg { channel: channel, msg: response })
+}
+
+fn tag_cmd<KV>(time: DateTime<UTC>, channel: String, from: String, tag: String, arg: Option<String>, recent: &HashMap<String, VecDeque<RecentLine>>, store: &mut Store<KV>) -> Result<ChatEffect, BotError> where KV: db::KV<String, String> {
+    lazy_static! {
+        static ref BACK: Regex = Regex::new(r"^\^([0-9]+)$").unwrap();
+    }
+
+    let lines = recent.get(&channel);
+    let found = match arg {
+        None => lines.and_then(|ls| ls.back()).cloned()
+            .ok_or(format!("No recent line in {} to tag", channel)),
+        Some(ref a) if BACK.is_match(a) => {
+            let n: usize = BACK.captures(a).unwrap().at(1).unwrap().parse().unwrap_or(0);
+            lines.and_then(|ls| if n >= 1 && n <= ls.len() { ls.get(ls.len() - n) } else { None }).cloned()
+                .ok_or(format!("No line {} messages back in {} to tag", n, channel))
+        },
+        Some(ref nick) if !nick.contains(char::is_whitespace) =>
+            lines.and_then(|ls| ls.iter().rev().find(|l| &l.from == nick)).cloned()
+                .ok_or(format!("No recent line from {} in {} to tag", nick, channel)),
+        Some(text) => Ok(RecentLine { from: from, msg: text, time: time }),
+    };
+
+    match found {
+        Ok(l) => tag_line(l.time, l.from, channel, vec![tag], l.msg, store),
+        Err(error) => Ok(ChatEffect::ChannelMsg { channel: channel, msg: vec![error] }),
+    }
+}
+
+fn untag_cmd<KV>(time: DateTime<UTC>, channel: String, from: String, tag: String, hash: String, privileged: bool, reply: Reply, store: &mut Store<KV>) -> Result<ChatEffect, BotError> where KV: db::KV<String, String> {
+    let key = mk_key(&channel, &tag, &hash);
+    match try!(store.get::<TaggedLine>(&key)) {
+        Some(tl) => {
+            if tl.user != from && !privileged {
+                let error = format!("{}: only {}, channel operators or admins may untag that line", from, tl.user);
+                return Ok(ChatEffect::ChannelMsg { channel: channel, msg: vec![error] })
+            }
+            let mut batch = typed::Batch::new();
+            batch.delete(&key);
+            unindex_line(&tl, &mut batch);
+            try!(adjust_line_count(&channel, &tag, -1, store, &mut batch));
+            let msg = vec![
+                format!("Removed tag {} from line: {}", tag, tl.line),
+                format!("Restore using: \"{}\"", reply.cmd("restore", &channel, format!("{} {}", tag, hash)))];
+            let trashed = TrashedLine { line: tl, deleted_by: from, deleted_at: time };
+            try!(batch.put(&mk_trash_key(&channel, &tag, &hash), &trashed));
+            try!(store.write(batch));
+            Ok(ChatEffect::ChannelMsg { channel: channel, msg: msg })
+        },
+        None => {
+            let error = format!("Unable to find line tagged with {} and with hash {}", tag, hash);
+            Ok(ChatEffect::ChannelMsg { channel: channel, msg: vec![error] })
+        },
+    }
+}
+
+fn restore_line<KV>(channel: String, trashed: TrashedLine, store: &mut Store<KV>) -> Result<ChatEffect, BotError> where KV: db::KV<String, String> {
+    let tl = trashed.line;
+    let mut batch = typed::Batch::new();
+    batch.delete(&mk_trash_key(&tl.channel, &tl.tag, &tl.hash));
+    if try!(put_line(&tl, store, &mut batch)) {
+        try!(adjust_line_count(&tl.channel, &tl.tag, 1, store, &mut batch));
+    }
+    try!(store.write(batch));
+    let msg = vec![format!("Restored tag {} on line: {}", tl.tag, tl.line)];
+    Ok(ChatEffect::ChannelMsg { channel: channel, msg: msg })
+}
+
+fn undo_cmd<KV>(channel: String, from: String, store: &mut Store<KV>) -> Result<ChatEffect, BotError> where KV: db::KV<String, String> {
+    let (trashed, _) = try!(collect_records(store.scan::<TrashedLine>(db::Scan::prefix(mk_trash_channel_prefix(&channel)))));
+    let latest = trashed.into_iter()
+        .map(|(_, t)| t)
+        .filter(|t| t.deleted_by == from && t.line.channel == channel)
+        .max_by_key(|t| t.deleted_at);
+    match latest {
+        Some(trashed) => restore_line(channel, trashed, store),
+        None => {
+            let error = format!("{}: nothing to undo", from);
+            Ok(ChatEffect::ChannelMsg { channel: channel, msg: vec![error] })
+        },
+    }
+}
+
+fn restore_cmd<KV>(channel: String, from: String, tag: String, hash: String, privileged: bool, store: &mut Store<KV>) -> Result<ChatEffect, BotError> where KV: db::KV<String, String> {
+    match try!(store.get::<TrashedLine>(&mk_trash_key(&channel, &tag, &hash))) {
+        Some(trashed) => {
+            if trashed.deleted_by != from && trashed.line.user != from && !privileged {
+                let error = format!("{}: only {}, {}, channel operators or admins may restore that line", from, trashed.line.user, trashed.deleted_by);
+                return Ok(ChatEffect::ChannelMsg { channel: channel, msg: vec![error] })
+            }
+            restore_line(channel, trashed, store)
+        },
+        None => {
+            let error = format!("Unable to find removed line tagged with {} and with hash {}", tag, hash);
+            Ok(ChatEffect::ChannelMsg { channel: channel, msg: vec![error] })
+        },
+    }
+}
+
+fn purge_trash<KV>(now: DateTime<UTC>, config: &Config, store: &mut Store<KV>) -> Result<(), BotError> where KV: db::KV<String, String> {
+    let cutoff = now - Duration::hours(config.trash_max_age_hours);
+    let mut batch = typed::Batch::new();
+    let (trashed, _) = try!(collect_records(store.scan::<TrashedLine>(db::Scan::prefix(mk_trash_prefix()))));
+    for (key, t) in trashed {
+        if t.deleted_at < cutoff {
+            batch.delete(&key)
+        }
+    }
+    if !batch.is_empty() {
+        try!(store.write(batch));
+    }
+    Ok(())
+}
+
+fn search_cmd<KV>(channel: String, query: String, store: &Store<KV>) -> Result<ChatEffect, BotError> where KV: db::KV<String, String> {
+    let mut hits: HashMap<String, usize> = HashMap::new();
+    for word in words(query.as_str()) {
+        for entry in store.kv().scan(db::Scan::prefix(mk_search_key_prefix(&channel, &word))) {
+            let (_, key) = try!(entry);
+            *hits.entry(key).or_insert(0) += 1;
+        }
+    }
+
+    let mut matches: Vec<(usize, TaggedLine)> = Vec::new();
+    let mut skipped = 0;
+    for (key, score) in hits {
+        match store.get::<TaggedLine>(&key) {
+            Ok(Some(tl)) => if tl.channel == channel { matches.push((score, tl)) },
+            Ok(None) => (),
+            Err(db::Error::Encoding(err)) => {
+                println!("Skipping unreadable record: {}", err);
+                skipped += 1
+            },
+            Err(err) => return Err(BotError::from(err)),
+        }
+    }
+    matches.sort_by(|a, b| (b.0, &b.1.time).cmp(&(a.0, &a.1.time)));
+
+    if matches.is_empty() {
+        let mut msg = vec![format!("No tagged lines matching: {}", query)];
+        report_skipped(skipped, &mut msg);
+        return Ok(ChatEffect::ChannelMsg { channel: channel, msg: msg })
+    }
+
+    let mut msg = matches.iter()
+        .take(SEARCH_MAX_RESULTS)
+        .map(|&(_, ref l)| format!(
+                "{} (tag: {}, by: {}, at: {}, hash: {})",
+                &l.line,
+                &l.tag,
+                &l.user,
+                &l.time.with_timezone(&Local).to_rfc2822(),
+                &l.hash))
+        .collect::<Vec<String>>();
+    msg.insert(0, format!("Search results for {} ({} of {}):", query, msg.len(), matches.len()));
+    report_skipped(skipped, &mut msg);
+    Ok(ChatEffect::ChannelMsg { channel: channel, msg: msg })
+}
+
+struct TagSummary {
+    tag: String,
+    count: usize,
+    last_time: DateTime<UTC>,
+    last_user: String,
+}
+
+fn tags_cmd<KV>(channel: String, by_count: bool, store: &Store<KV>) -> Result<ChatEffect, BotError> where KV: db::KV<String, String> {
+    let mut summaries: Vec<TagSummary> = Vec::new();
+    let (tagged_lines, skipped) = try!(collect_records(store.scan::<TaggedLine>(db::Scan::prefix(mk_channel_prefix(&channel)))));
+    for (_, tl) in tagged_lines {
+        if tl.channel != channel {
+            continue
+        }
+        match summaries.iter_mut().find(|s| s.tag == tl.tag) {
+            Some(s) => {
+                s.count += 1;
+                if tl.time > s.last_time {
+                    s.last_time = tl.time;
+                    s.last_user = tl.user.clone();
+                }
+                continue
+            },
+            None => (),
+        }
+        summaries.push(TagSummary { tag: tl.tag, count: 1, last_time: tl.time, last_user: tl.user });
+    }
+
+    if summaries.is_empty() {
+        let mut msg = vec![format!("No tags in {}", channel)];
+        report_skipped(skipped, &mut msg);
+        return Ok(ChatEffect::ChannelMsg { channel: channel, msg: msg })
+    }
+
+    if by_count {
+        summaries.sort_by(|a, b| (b.count, &b.last_time).cmp(&(a.count, &a.last_time)));
+    } else {
+        summaries.sort_by(|a, b| b.last_time.cmp(&a.last_time));
+    }
+
+    let mut msg = summaries.iter()
+        .map(|s| format!(
+                "{} ({} lines, last by: {}, at: {})",
+                &s.tag,
+                s.count,
+                &s.last_user,
+                &s.last_time.with_timezone(&Local).to_rfc2822()))
+        .collect::<Vec<String>>();
+    msg.insert(0, format!("Tags in {}:", channel));
+    report_skipped(skipped, &mut msg);
+    Ok(ChatEffect::ChannelMsg { channel: channel, msg: msg })
 }
 
+#[derive(Default)]
+struct ExportFilter {
+    channel: Option<String>,
+    tag: Option<String>,
+    since: Option<DateTime<UTC>>,
+    until: Option<DateTime<UTC>>,
+}
 
-fn list_cmd<KV>(channel: String, tag: String, kv: &KV) -> ChatEffect where KV: db::KV<String, String> {
+impl ExportFilter {
+    fn prefix(&self) -> String {
+        match (self.channel.as_ref(), self.tag.as_ref()) {
+            (Some(channel), Some(tag)) => mk_key_prefix(channel, tag),
+            (Some(channel), None) => mk_channel_prefix(channel),
+            (None, _) => mk_lines_prefix(),
+        }
+    }
+
+    fn matches(&self, tl: &TaggedLine) -> bool {
+        self.tag.as_ref().map_or(true, |tag| &tl.tag == tag) &&
+            self.since.map_or(true, |since| tl.time >= since) &&
+            self.until.map_or(true, |until| tl.time < until)
+    }
+}
+
+fn export_lines<KV, W>(store: &Store<KV>, filter: &ExportFilter, out: &mut W) -> Result<usize, BotError> where KV: db::KV<String, String>, W: Write {
+    let mut exported = 0;
+    for record in store.scan::<TaggedLine>(db::Scan::prefix(filter.prefix())) {
+        match record {
+            Ok((key, tl)) => if filter.matches(&tl) {
+                try!(writeln!(out, "{}", try!(typed::encode(&key, &tl))));
+                exported += 1
+            },
+            Err(db::Error::Encoding(err)) => { let _ = writeln!(io::stderr(), "Skipping unreadable record: {}", err); },
+            Err(err) => return Err(BotError::from(err)),
+        }
+    }
+    Ok(exported)
+}
+
+/// Writes nothing unless every line parses; already tagged lines are skipped.
+fn import_lines<KV, R>(store: &mut Store<KV>, input: R) -> Result<usize, BotError> where KV: db::KV<String, String>, R: BufRead {
+    let mut tagged_lines = Vec::new();
+    for (n, line) in input.lines().enumerate() {
+        let line = try!(line);
+        if line.trim().is_empty() {
+            continue
+        }
+        let tl = try!(serde_json::from_str::<TaggedLine>(&line)
+            .map_err(|e| db::Error::Encoding(format!("unable to import line {}: {}", n + 1, e))));
+        tagged_lines.push(tl);
+    }
+
+    let mut batch = typed::Batch::new();
+    let mut imported = Vec::new();
+    let mut counts: HashMap<(String, String), isize> = HashMap::new();
+    for tl in tagged_lines {
+        let key = mk_key(&tl.channel, &tl.tag, &tl.hash);
+        if imported.contains(&key) || try!(store.kv().get(&key)).is_some() {
+            continue
+        }
+        try!(batch.put(&key, &tl));
+        index_line(&tl, &mut batch);
+        *counts.entry((tl.channel, tl.tag)).or_insert(0) += 1;
+        imported.push(key);
+    }
+    for ((channel, tag), count) in counts {
+        try!(adjust_line_count(&channel, &tag, count, store, &mut batch));
+    }
+    try!(store.write(batch));
+    Ok(imported.len())
+}
+
+#[derive(Default)]
+struct Snapshots {
+    last: Option<DateTime<UTC>>,
+    writing: Option<(DateTime<UTC>, Receiver<Result<(), BotError>>)>,
+}
+
+impl Snapshots {
+    fn finish(&mut self, wait: bool) -> Result<(), BotError> {
+        let result = match self.writing {
+            Some((_, ref done)) if wait => done.recv().ok(),
+            Some((_, ref done)) => match done.try_recv() {
+                Ok(result) => Some(result),
+                Err(TryRecvError::Empty) => return Ok(()),
+                Err(TryRecvError::Disconnected) => None,
+            },
+            None => return Ok(()),
+        };
+        let (started, _) = self.writing.take().unwrap();
+        match result {
+            Some(Ok(())) => {
+                self.last = Some(started);
+                Ok(())
+            },
+            Some(Err(err)) => Err(err),
+            None => Err(BotError::Io(io::Error::new(io::ErrorKind::Other, "snapshot writer panicked"))),
+        }
+    }
+}
+
+/// Starts writing every key-value pair as a log store on a background
+/// thread; a failed write is reported by a later call and then retried.
+fn snapshot<KV>(now: DateTime<UTC>, config: &Config, snapshots: &mut Snapshots, store: &Store<KV>) -> Result<(), BotError> where KV: db::KV<String, String> {
+    let dir = match config.snapshot_dir {
+        Some(ref dir) => Path::new(dir).to_path_buf(),
+        None => return Ok(()),
+    };
+    try!(snapshots.finish(false));
+    if snapshots.writing.is_some() || snapshots.last.map_or(false, |t| now - t < Duration::hours(config.snapshot_interval_hours)) {
+        return Ok(())
+    }
+
+    let view = try!(store.kv().snapshot());
+    let name = format!("tag_bot_db-{}.log", now.format("%Y%m%dT%H%M%SZ"));
+    let keep = config.snapshot_keep;
+    let (done, finished) = channel();
+    thread::spawn(move || {
+        let _ = done.send(write_snapshot(&dir, &name, &*view, keep));
+    });
+    snapshots.writing = Some((now, finished));
+    Ok(())
+}
+
+fn write_snapshot(dir: &Path, name: &str, view: &db::Snapshot, keep: usize) -> Result<(), BotError> {
+    let tmp = dir.join(format!("{}.tmp", name));
+    if tmp.exists() {
+        try!(fs::remove_file(&tmp));
+    }
+    try!(db::log_kv::LogKV::open(&tmp).and_then(|mut log| view.copy_to(&mut log)));
+    try!(fs::rename(&tmp, dir.join(name)));
+
+    let mut snapshots = Vec::new();
+    for entry in try!(fs::read_dir(dir)) {
+        let file_name = try!(entry).file_name().to_string_lossy().into_owned();
+        if file_name.starts_with("tag_bot_db-") && file_name.ends_with(".log") {
+            snapshots.push(file_name)
+        }
+    }
+    snapshots.sort();
+    let stale = snapshots.len().saturating_sub(keep);
+    for file_name in snapshots.into_iter().take(stale) {
+        try!(fs::remove_file(dir.join(file_name)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+fn test_bot() -> TagBot<db::hashmap_kv::HashMapKV> {
+    TagBot::new(db::hashmap_kv::HashMapKV::new(), Config::default())
+}
+
+#[test]
+fn save_line_without_tag_test() {
+    let mut bot = test_bot();
+    let input = Event::Event { time: UTC::now(), event: ChatEvent::ChannelMsg {
+        channel: "my_channel".to_owned(),
+        from: "user1".to_owned(),
+        msg: "test line tag".to_owned() } };
+
+    assert_eq!(tag_bot(input, &mut bot), None)
+}
+
+#[test]
+fn ignore_line_with_almost_tags_test() {
+    let mut bot = test_bot();
+    let input = Event::Event { time: UTC::now(), event: ChatEvent::ChannelMsg {
+        channel: "my_channel".to_owned(),
+        from: "user1".to_owned(),
+        msg: "not#tag #not(a-tag)".to_owned() } };
+
+    assert_eq!(tag_bot(input, &mut bot), None)
+}
+
+#[test]
+fn save_line_with_tag_in_the_middle_test() {
+    let tag = "#tag".to_owned();
+    save_line_with_tag_test(format!("test line {} some more text", tag), tag)
+}
+
+#[test]
+fn save_line_with_tag_at_the_end_test() {
+    let tag = "#tag".to_owned();
+    save_line_with_tag_test(format!("another test line {}", tag), tag)
+}
+
+#[test]
+fn save_line_with_tag_at_the_start_test() {
+    let tag = "#tag".to_owned();
+    save_line_with_tag_test(format!("{} yet another test line", tag), tag)
+}
+
+
+#[cfg(test)]
+fn save_line_with_tag_test(line: String, tag: String) {
+    let mut bot = test_bot();
+    let channel = "my_channel".to_owned();
+    let input = Event::Event { time: UTC::now(), event: ChatEvent::ChannelMsg {
+        channel: channel.clone(),
+        from: "user1".to_owned(),
+        msg: line.clone() } };
+
+    match tag_bot(input, &mut bot) {
+        Some(Effect::Effect(ChatEffect::ChannelMsg { channel: to_channel, msg })) => {
+            assert_eq!(to_channel, channel);
+            assert!(msg[0].contains(hash(&line).as_str()));
+        },
+        _ => panic!(),
+    }
+
+    let expected_key = mk_key(&channel, &tag, &hash(&line));
+    match bot.store.get::<TaggedLine>(&expected_key).unwrap() {
+        Some(tagged_line) => assert_eq!(tagged_line.line, line),
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn save_line_with_several_tags_test() {
+    let mut bot = test_bot();
+    let channel = "my_channel".to_owned();
+    let line = "deploy broke #ops #incident".to_owned();
+    let time = UTC::now();
+    run_tag_bot_for_line_in_channel(&time, &channel, &"user1".to_owned(), &line, &mut bot);
+
+    for tag in vec!["#ops", "#incident"] {
+        let key = mk_key(&channel, &tag.to_owned(), &hash(&line));
+        let tagged_line: TaggedLine = bot.store.get(&key).unwrap().unwrap();
+        assert_eq!(tagged_line.tag, tag);
+        assert_eq!(tagged_line.line, line);
+    }
+}
+
+#[test]
+fn confirm_every_tag_test() {
+    let mut bot = test_bot();
+    let line = "#ops and #incident".to_owned();
+    let input = Event::Event { time: UTC::now(), event: ChatEvent::ChannelMsg {
+        channel: "my_channel".to_owned(),
+        from: "user1".to_owned(),
+        msg: line.clone() } };
+
+    match tag_bot(input, &mut bot) {
+        Some(Effect::Effect(ChatEffect::ChannelMsg { msg, .. })) => {
+            assert_eq!(msg.len(), 2);
+            assert!(msg[0].contains(format!("!untag #ops {}", hash(&line)).as_str()));
+            assert!(msg[1].contains(format!("!untag #incident {}", hash(&line)).as_str()));
+        },
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn find_repeated_tags_once_test() {
+    assert_eq!(find_tags("#ops again #ops and #ops"), vec!["#ops".to_owned()])
+}
+
+#[test]
+fn find_adjacent_tags_test() {
+    assert_eq!(find_tags("broke #ops #incident #db"), vec!["#ops".to_owned(), "#incident".to_owned(), "#db".to_owned()])
+}
+
+#[test]
+fn find_punctuation_delimited_tags_test() {
+    assert_eq!(find_tags("see #ops,#incident; also #db. #why? #now!"),
+        vec!["#ops".to_owned(), "#incident".to_owned(), "#db".to_owned(), "#why".to_owned(), "#now".to_owned()])
+}
+
+#[test]
+fn find_no_tags_in_almost_tags_test() {
+    assert!(find_tags("not#tag #not(a-tag) #").is_empty())
+}
+
+#[test]
+fn recall_tag_one_line_test() {
+    let mut bot = test_bot();
+    let channel = "my_channel".to_owned();
+    let tag = "#tag".to_owned();
+    let line = format!("a test line {}", tag);
+
+    let input_line = Event::Event { time: UTC::now(), event: ChatEvent::ChannelMsg {
+        channel: channel.clone(),
+        from: "user1".to_owned(),
+        msg: line.clone() } };
+    match tag_bot(input_line, &mut bot) {
+        Some(_) => (),
+        _ => panic!(),
+    }
+
+    let recall_cmdline = format!("!list {}", tag);
+    let recall_event = Event::Event { time: UTC::now(), event: ChatEvent::ChannelMsg {
+        channel: channel.clone(),
+        from: "user2".to_owned(),
+        msg: recall_cmdline } };
+
+    match tag_bot(recall_event, &mut bot) {
+        Some(Effect::Effect(ChatEffect::ChannelMsg { channel: to_channel, msg })) => {
+            assert_eq!(to_channel, channel);
+            assert!(msg[0].contains(tag.as_str()));
+            assert!(msg[1].contains(line.as_str()));
+        },
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn recall_tag_several_lines_test() {
+    let mut bot = test_bot();
+    let channel = "my_channel".to_owned();
+    let tag = "#tag".to_owned();
+
+    let line1 = format!("a test line {}", tag);
+    let time1 = UTC::now() - Duration::hours(3);
+    let user1 = "user1".to_owned();
+    let hash1 = hash(&line1);
+    run_tag_bot_for_line_in_channel(&time1, &channel, &user1, &line1, &mut bot);
+
+    let line2 = format!("another {} test line", tag);
+    let time2 = time1 + Duration::hours(1);
+    let user2 = "user2".to_owned();
+    let hash2 = hash(&line2);
+    run_tag_bot_for_line_in_channel(&time2, &channel, &user2, &line2, &mut bot);
+
+    let line3 = format!("{} yet another line", tag);
+    let time3 = time2 + Duration::hours(1);
+    let user3 = "user3".to_owned();
+    let hash3 = hash(&line3);
+    run_tag_bot_for_line_in_channel(&time3, &channel, &user3, &line3, &mut bot);
+
+    assert!(time1 < time2);
+    assert!(time2 < time3);
+
+    let recall_cmdline = format!("!list {}", tag);
+    let recall_event = Event::Event { time: UTC::now(), event: ChatEvent::ChannelMsg {
+        channel: channel.clone(),
+        from: "user4".to_owned(),
+        msg: recall_cmdline } };
+
+    match tag_bot(recall_event, &mut bot) {
+        Some(Effect::Effect(ChatEffect::ChannelMsg { channel: to_channel, msg })) => {
+            assert_eq!(to_channel, channel);
+            assert_eq!(msg[0], format!("Listing tag {}:", tag));
+
+            assert!(msg[1].contains(line1.as_str()));
+            assert!(msg[1].contains(user1.as_str()));
+            assert!(msg[1].contains(&time1.with_timezone(&Local).to_rfc2822()));
+            assert!(msg[1].contains(hash1.as_str()));
+
+            assert!(msg[2].contains(line2.as_str()));
+            assert!(msg[2].contains(user2.as_str()));
+            assert!(msg[2].contains(&time2.with_timezone(&Local).to_rfc2822()));
+            assert!(msg[2].contains(hash2.as_str()));
+
+            assert!(msg[3].contains(line3.as_str()));
+            assert!(msg[3].contains(user3.as_str()));
+            assert!(msg[3].contains(&time3.with_timezone(&Local).to_rfc2822()));
+            assert!(msg[3].contains(hash3.as_str()));
+        },
+        _ => panic!(),
+    }
+}
+
+#[cfg(test)]
+fn tag_lines_hourly(n: usize, channel: &String, tag: &String, bot: &mut TagBot<db::hashmap_kv::HashMapKV>) -> DateTime<UTC> {
+    let start = UTC::now() - Duration::hours(n as i64);
+    for i in 0..n {
+        let line = format!("line {} {}", i, tag);
+        run_tag_bot_for_line_in_channel(&(start + Duration::hours(i as i64)), channel, &"user1".to_owned(), &line, bot);
+    }
+    start
+}
+
+#[test]
+fn paginate_recalled_lines_test() {
+    let mut bot = test_bot();
+    let channel = "my_channel".to_owned();
+    let tag = "#tag".to_owned();
+    tag_lines_hourly(15, &channel, &tag, &mut bot);
+
+    let recall_event = Event::Event { time: UTC::now(), event: ChatEvent::ChannelMsg {
+        channel: channel.clone(),
+        from: "user2".to_owned(),
+        msg: format!("!list {}", tag) } };
+    match tag_bot(recall_event, &mut bot) {
+        Some(Effect::Effect(ChatEffect::ChannelMsg { msg, .. })) => {
+            assert_eq!(msg.len(), 12);
+            assert!(msg[1].starts_with("line 0 "));
+            assert!(msg[10].starts_with("line 9 "));
+            assert_eq!(msg[11], format!("Showing 1-10 of 15, use \"!list {} 2\" for more", tag));
+        },
+        _ => panic!(),
+    }
+
+    let recall_event = Event::Event { time: UTC::now(), event: ChatEvent::ChannelMsg {
+        channel: channel.clone(),
+        from: "user2".to_owned(),
+        msg: format!("!list {} 2", tag) } };
+    match tag_bot(recall_event, &mut bot) {
+        Some(Effect::Effect(ChatEffect::ChannelMsg { msg, .. })) => {
+            assert_eq!(msg.len(), 7);
+            assert!(msg[1].starts_with("line 10 "));
+            assert!(msg[5].starts_with("line 14 "));
+            assert_eq!(msg[6], "Showing 11-15 of 15".to_owned());
+        },
+        _ => panic!(),
+    }
+
+    let recall_event = Event::Event { time: UTC::now(), event: ChatEvent::ChannelMsg {
+        channel: channel.clone(),
+        from: "user2".to_owned(),
+        msg: format!("!list {} 3", tag) } };
+    match tag_bot(recall_event, &mut bot) {
+        Some(Effect::Effect(ChatEffect::ChannelMsg { msg, .. })) => {
+            assert_eq!(msg, vec![format!("No page 3 for tag {}, it has 15 lines", tag)]);
+        },
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn reject_pages_past_usize_test() {
+    let mut bot = test_bot();
+    bot.config.page_size = std::usize::MAX;
+    let channel = "my_channel".to_owned();
+    let tag = "#tag".to_owned();
+    tag_lines_hourly(2, &channel, &tag, &mut bot);
+
+    let page = std::usize::MAX;
+    let recall_event = ChatEvent::ChannelMsg { channel: channel.clone(), from: "user2".to_owned(), msg: format!("!list {} {}", tag, page) };
+    match run_tag_bot_for_event(recall_event, &mut bot) {
+        Some(Effect::Effect(ChatEffect::ChannelMsg { msg, .. })) => assert_eq!(msg, vec![format!("No page {} for tag {}, it has 2 lines", page, tag)]),
+        _ => panic!(),
+    }
+
+    let recall_event = ChatEvent::ChannelMsg { channel: channel.clone(), from: "user2".to_owned(), msg: format!("!list {}", tag) };
+    match run_tag_bot_for_event(recall_event, &mut bot) {
+        Some(Effect::Effect(ChatEffect::ChannelMsg { msg, .. })) => assert_eq!(msg.len(), 3),
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn reject_empty_pages_in_config_test() {
+    assert!(Config::default().check().is_ok());
+    assert!(Config { page_size: 0, .. Config::default() }.check().is_err());
+}
+
+#[test]
+fn recall_newest_lines_first_test() {
+    let mut bot = test_bot();
+    bot.config.page_size = 5;
+    bot.config.newest_first = true;
+    let channel = "my_channel".to_owned();
+    let tag = "#tag".to_owned();
+    tag_lines_hourly(7, &channel, &tag, &mut bot);
+
+    let recall_event = Event::Event { time: UTC::now(), event: ChatEvent::ChannelMsg {
+        channel: channel.clone(),
+        from: "user2".to_owned(),
+        msg: format!("!list {}", tag) } };
+    match tag_bot(recall_event, &mut bot) {
+        Some(Effect::Effect(ChatEffect::ChannelMsg { msg, .. })) => {
+            assert_eq!(msg.len(), 7);
+            assert!(msg[1].starts_with("line 6 "));
+            assert!(msg[5].starts_with("line 2 "));
+            assert_eq!(msg[6], format!("Showing 1-5 of 7, use \"!list {} 2\" for more", tag));
+        },
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn send_long_listings_privately_test() {
+    let mut bot = test_bot();
+    bot.config.private_threshold = Some(3);
+    let channel = "my_channel".to_owned();
+    let tag = "#tag".to_owned();
+    tag_lines_hourly(4, &channel, &tag, &mut bot);
+
+    let recall_event = Event::Event { time: UTC::now(), event: ChatEvent::ChannelMsg {
+        channel: channel.clone(),
+        from: "user2".to_owned(),
+        msg: format!("!list {}", tag) } };
+    match tag_bot(recall_event, &mut bot) {
+        Some(Effect::Effect(ChatEffect::Many(effs))) => {
+            assert_eq!(effs.len(), 2);
+            match effs[0] {
+                ChatEffect::PrivateMsg { ref to, ref msg } => {
+                    assert_eq!(to, "user2");
+                    assert_eq!(msg.len(), 5);
+                    assert_eq!(msg[0], format!("Listing tag {} in {}:", tag, channel));
+                    assert!(msg[4].starts_with("line 3 "));
+                },
+                _ => panic!(),
+            }
+            assert_eq!(effs[1], ChatEffect::ChannelMsg { channel: channel, msg: vec!["user2: sent you 4 of 4 lines".to_owned()] });
+        },
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn page_private_listings_like_channel_listings_test() {
+    let mut bot = test_bot();
+    bot.config.private_threshold = Some(3);
+    bot.config.page_size = 3;
+    let channel = "my_channel".to_owned();
+    let tag = "#tag".to_owned();
+    tag_lines_hourly(7, &channel, &tag, &mut bot);
+
+    let list_page = |page: usize, bot: &mut TagBot<db::hashmap_kv::HashMapKV>| {
+        let recall_event = ChatEvent::ChannelMsg { channel: channel.clone(), from: "user2".to_owned(), msg: format!("!list {} {}", tag, page) };
+        match run_tag_bot_for_event(recall_event, bot) {
+            Some(Effect::Effect(ChatEffect::Many(mut effs))) => match effs.remove(0) {
+                ChatEffect::PrivateMsg { msg, .. } => msg,
+                _ => panic!(),
+            },
+            _ => panic!(),
+        }
+    };
+
+    let first = list_page(1, &mut bot);
+    assert_eq!(first.len(), 5);
+    assert_eq!(first[4], format!("Showing 1-3 of 7, use \"!list {} 2\" in {} for more", tag, channel));
+
+    let last = list_page(3, &mut bot);
+    assert_eq!(last.len(), 2);
+    assert!(last[1].starts_with("line 6 "));
+}
+
+#[test]
+fn keep_short_listings_in_channel_test() {
+    let mut bot = test_bot();
+    bot.config.private_threshold = Some(3);
+    let channel = "my_channel".to_owned();
+    let tag = "#tag".to_owned();
+    tag_lines_hourly(3, &channel, &tag, &mut bot);
+
+    let recall_event = Event::Event { time: UTC::now(), event: ChatEvent::ChannelMsg {
+        channel: channel.clone(),
+        from: "user2".to_owned(),
+        msg: format!("!list {}", tag) } };
+    match tag_bot(recall_event, &mut bot) {
+        Some(Effect::Effect(ChatEffect::ChannelMsg { msg, .. })) => assert_eq!(msg.len(), 4),
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn untag_nonexisting_line_comlains_test() {
+    let mut bot = test_bot();
+    let channel = "my_channel".to_owned();
+    let tag = "#tag".to_owned();
+
+    let nonexisting_hash = "abbababe";
+    let untag_cmdline = format!("!untag {} {}", tag, nonexisting_hash);
+    let untag_event = Event::Event { time: UTC::now(), event: ChatEvent::ChannelMsg {
+        channel: channel.clone(),
+        from: "user3".to_owned(),
+        msg: untag_cmdline } };
+
+    match tag_bot(untag_event, &mut bot) {
+        Some(Effect::Effect(ChatEffect::ChannelMsg { msg, .. })) => {
+            let error = format!("Unable to find line tagged with {} and with hash {}", tag, nonexisting_hash);
+            assert_eq!(msg[0], error);
+        },
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn untag_line_test() {
+    let mut bot = test_bot();
+    let channel = "my_channel".to_owned();
+    let tag = "#tag".to_owned();
+
+    let line1 = format!("a test line {}", tag);
+    let time1 = UTC::now() - Duration::hours(3);
+    let user1 = "user1".to_owned();
+    let line1_hash = hash(&line1);
+    run_tag_bot_for_line_in_channel(&time1, &channel, &user1, &line1, &mut bot);
+
+    let expected_key = mk_key(&channel, &tag, &line1_hash);
+    assert!(bot.store.kv().get(&expected_key).unwrap().is_some());
+
+    let untag_cmdline = format!("!untag {} {}", tag, line1_hash);
+    let untag_event = Event::Event { time: UTC::now(), event: ChatEvent::ChannelMsg {
+        channel: channel.clone(),
+        from: user1.clone(),
+        msg: untag_cmdline } };
+
+    match tag_bot(untag_event, &mut bot) {
+        Some(Effect::Effect(ChatEffect::ChannelMsg { msg, .. })) => {
+            assert_eq!(msg[0], format!("Removed tag {} from line: {}", tag, line1));
+        }
+        _ => panic!(),
+    }
+
+    let expected_key = mk_key(&channel, &tag, &line1_hash);
+    assert!(bot.store.kv().get(&expected_key).unwrap().is_none());
+}
+
+#[test]
+fn search_ranks_lines_by_matching_words_test() {
+    let mut bot = test_bot();
+    let channel = "my_channel".to_owned();
+    let time = UTC::now() - Duration::hours(3);
+
+    let line1 = "the deploy broke #ops".to_owned();
+    run_tag_bot_for_line_in_channel(&time, &channel, &"user1".to_owned(), &line1, &mut bot);
+    let line2 = "Deploy of the database broke #incident".to_owned();
+    run_tag_bot_for_line_in_channel(&(time + Duration::hours(1)), &channel, &"user2".to_owned(), &line2, &mut bot);
+    let line3 = "unrelated #ops".to_owned();
+    run_tag_bot_for_line_in_channel(&time, &channel, &"user3".to_owned(), &line3, &mut bot);
+
+    let search_event = Event::Event { time: UTC::now(), event: ChatEvent::ChannelMsg {
+        channel: channel.clone(),
+        from: "user4".to_owned(),
+        msg: "!search database deploy".to_owned() } };
+
+    match tag_bot(search_event, &mut bot) {
+        Some(Effect::Effect(ChatEffect::ChannelMsg { channel: to_channel, msg })) => {
+            assert_eq!(to_channel, channel);
+            assert_eq!(msg.len(), 3);
+            assert!(msg[1].contains(line2.as_str()));
+            assert!(msg[1].contains("#incident"));
+            assert!(msg[1].contains("user2"));
+            assert!(msg[1].contains(hash(&line2).as_str()));
+            assert!(msg[2].contains(line1.as_str()));
+        },
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn search_only_current_channel_test() {
+    let mut bot = test_bot();
+    let line = "the deploy broke #ops".to_owned();
+    run_tag_bot_for_line_in_channel(&UTC::now(), &"other_channel".to_owned(), &"user1".to_owned(), &line, &mut bot);
+
+    let search_event = Event::Event { time: UTC::now(), event: ChatEvent::ChannelMsg {
+        channel: "my_channel".to_owned(),
+        from: "user2".to_owned(),
+        msg: "!search deploy".to_owned() } };
+
+    match tag_bot(search_event, &mut bot) {
+        Some(Effect::Effect(ChatEffect::ChannelMsg { msg, .. })) => {
+            assert_eq!(msg, vec!["No tagged lines matching: deploy".to_owned()]);
+        },
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn search_forgets_untagged_lines_test() {
+    let mut bot = test_bot();
+    let channel = "my_channel".to_owned();
+    let tag = "#ops".to_owned();
+    let line = format!("the deploy broke {}", tag);
+    run_tag_bot_for_line_in_channel(&UTC::now(), &channel, &"user1".to_owned(), &line, &mut bot);
+    run_tag_bot_for_line_in_channel(&UTC::now(), &channel, &"user1".to_owned(), &format!("!untag {} {}", tag, hash(&line)), &mut bot);
+
+    assert!(bot.store.kv().get_prefix(&mk_search_key_prefix(&channel, &"deploy".to_owned())).unwrap().is_empty());
+}
+
+#[test]
+fn list_tags_by_recency_test() {
+    let mut bot = test_bot();
+    let channel = "my_channel".to_owned();
+    let time = UTC::now() - Duration::hours(3);
+
+    run_tag_bot_for_line_in_channel(&time, &channel, &"user1".to_owned(), &"first #ops".to_owned(), &mut bot);
+    run_tag_bot_for_line_in_channel(&(time + Duration::hours(1)), &channel, &"user2".to_owned(), &"second #ops".to_owned(), &mut bot);
+    run_tag_bot_for_line_in_channel(&(time + Duration::hours(2)), &channel, &"user3".to_owned(), &"only #incident".to_owned(), &mut bot);
+    run_tag_bot_for_line_in_channel(&time, &"my_channel-2".to_owned(), &"user4".to_owned(), &"elsewhere #ops".to_owned(), &mut bot);
+
+    let tags_event = Event::Event { time: UTC::now(), event: ChatEvent::ChannelMsg {
+        channel: channel.clone(),
+        from: "user4".to_owned(),
+        msg: "!tags".to_owned() } };
+
+    match tag_bot(tags_event, &mut bot) {
+        Some(Effect::Effect(ChatEffect::ChannelMsg { channel: to_channel, msg })) => {
+            assert_eq!(to_channel, channel);
+            assert_eq!(msg.len(), 3);
+            assert_eq!(msg[0], format!("Tags in {}:", channel));
+            assert!(msg[1].starts_with("#incident (1 lines, last by: user3"));
+            assert!(msg[2].starts_with("#ops (2 lines, last by: user2"));
+            assert!(msg[2].contains(&(time + Duration::hours(1)).with_timezone(&Local).to_rfc2822()));
+        },
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn list_tags_by_count_test() {
+    let mut bot = test_bot();
+    let channel = "my_channel".to_owned();
+    let time = UTC::now() - Duration::hours(3);
+
+    run_tag_bot_for_line_in_channel(&time, &channel, &"user1".to_owned(), &"first #ops".to_owned(), &mut bot);
+    run_tag_bot_for_line_in_channel(&time, &channel, &"user2".to_owned(), &"second #ops".to_owned(), &mut bot);
+    run_tag_bot_for_line_in_channel(&(time + Duration::hours(2)), &channel, &"user3".to_owned(), &"only #incident".to_owned(), &mut bot);
+
+    let tags_event = Event::Event { time: UTC::now(), event: ChatEvent::ChannelMsg {
+        channel: channel.clone(),
+        from: "user4".to_owned(),
+        msg: "!tags count".to_owned() } };
+
+    match tag_bot(tags_event, &mut bot) {
+        Some(Effect::Effect(ChatEffect::ChannelMsg { msg, .. })) => {
+            assert!(msg[1].starts_with("#ops (2 lines"));
+            assert!(msg[2].starts_with("#incident (1 lines"));
+        },
+        _ => panic!(),
+    }
+}
+
+#[cfg(test)]
+fn run_tag_bot_for_private_msg<KV>(user: &String, msg: &String, bot: &mut TagBot<KV>) -> Vec<String> where KV: db::KV<String, String> {
+    let input = Event::Event { time: UTC::now(), event: ChatEvent::PrivateMsg {
+        from: user.clone(),
+        msg: msg.clone() } };
+    match tag_bot(input, bot) {
+        Some(Effect::Effect(ChatEffect::PrivateMsg { to, msg })) => {
+            assert_eq!(&to, user);
+            msg
+        },
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn list_privately_test() {
+    let mut bot = test_bot();
+    bot.config.private_threshold = Some(1);
+    let channel = "#my_channel".to_owned();
+    let line = "a test line #tag".to_owned();
+    run_tag_bot_for_line_in_channel(&UTC::now(), &channel, &"user1".to_owned(), &line, &mut bot);
+    run_tag_bot_for_line_in_channel(&UTC::now(), &channel, &"user1".to_owned(), &"another #tag".to_owned(), &mut bot);
+    run_tag_bot_for_event(ChatEvent::JoinedChannel { channel: channel.clone(), who: "user2".to_owned() }, &mut bot);
+
+    let msg = run_tag_bot_for_private_msg(&"user2".to_owned(), &format!("!list {} #tag", channel), &mut bot);
+    assert_eq!(msg.len(), 3);
+    assert_eq!(msg[0], "Listing tag #tag:".to_owned());
+
+    bot.config.page_size = 1;
+    let msg = run_tag_bot_for_private_msg(&"user2".to_owned(), &format!("!list {} #tag", channel), &mut bot);
+    assert!(msg[1].contains(&format!("untag: \"!untag {} #tag ", channel)));
+    assert_eq!(msg[2], format!("Showing 1-1 of 2, use \"!list {} #tag 2\" for more", channel));
+}
+
+#[test]
+fn search_and_list_tags_privately_test() {
+    let mut bot = test_bot();
+    let channel = "#my_channel".to_owned();
+    let line = "the deploy broke #ops".to_owned();
+    run_tag_bot_for_line_in_channel(&UTC::now(), &channel, &"user1".to_owned(), &line, &mut bot);
+    run_tag_bot_for_event(ChatEvent::JoinedChannel { channel: channel.clone(), who: "user2".to_owned() }, &mut bot);
+
+    let msg = run_tag_bot_for_private_msg(&"user2".to_owned(), &format!("!search {} deploy", channel), &mut bot);
+    assert!(msg[1].contains(line.as_str()));
+
+    let msg = run_tag_bot_for_private_msg(&"user2".to_owned(), &format!("!tags {}", channel), &mut bot);
+    assert_eq!(msg[0], format!("Tags in {}:", channel));
+    assert!(msg[1].starts_with("#ops (1 lines"));
+}
+
+#[test]
+fn untag_privately_test() {
+    let mut bot = test_bot();
+    let channel = "#my_channel".to_owned();
+    let line = "the deploy broke #ops".to_owned();
+    run_tag_bot_for_line_in_channel(&UTC::now(), &channel, &"user1".to_owned(), &line, &mut bot);
+    run_tag_bot_for_event(ChatEvent::JoinedChannel { channel: channel.clone(), who: "user1".to_owned() }, &mut bot);
+
+    let msg = run_tag_bot_for_private_msg(&"user1".to_owned(), &format!("!untag {} #ops {}", channel, hash(&line)), &mut bot);
+    assert_eq!(msg[0], format!("Removed tag #ops from line: {}", line));
+    assert_eq!(msg[1], format!("Restore using: \"!restore {} #ops {}\"", channel, hash(&line)));
+    assert!(bot.store.kv().get(&mk_key(&channel, &"#ops".to_owned(), &hash(&line))).unwrap().is_none());
+}
+
+#[test]
+fn refuse_private_commands_outside_channel_test() {
+    let mut bot = test_bot();
+    bot.config.admins = vec!["admin".to_owned()];
+    let channel = "#my_channel".to_owned();
+    run_tag_bot_for_line_in_channel(&UTC::now(), &channel, &"user1".to_owned(), &"secret plans #ops".to_owned(), &mut bot);
+    run_tag_bot_for_event(ChatEvent::JoinedChannel { channel: "#other".to_owned(), who: "user2".to_owned() }, &mut bot);
+
+    let refusal = vec![format!("You need to be in {} to do that", channel)];
+    for cmd in vec![format!("!list {} #ops", channel), format!("!search {} plans", channel), format!("!tags {}", channel)] {
+        assert_eq!(run_tag_bot_for_private_msg(&"user2".to_owned(), &cmd, &mut bot), refusal);
+    }
+
+    let msg = run_tag_bot_for_private_msg(&"admin".to_owned(), &format!("!list {} #ops", channel), &mut bot);
+    assert!(msg[1].contains("secret plans"));
+}
+
+#[test]
+fn ignore_unknown_private_msg_test() {
+    let mut bot = test_bot();
+    let input = Event::Event { time: UTC::now(), event: ChatEvent::PrivateMsg {
+        from: "user1".to_owned(),
+        msg: "hello #tag".to_owned() } };
+    assert_eq!(tag_bot(input, &mut bot), None)
+}
+
+#[cfg(test)]
+fn run_tag_bot_for_event<KV>(event: ChatEvent, bot: &mut TagBot<KV>) -> Option<Effect<ChatEffect, ()>> where KV: db::KV<String, String> {
+    tag_bot(Event::Event { time: UTC::now(), event: event }, bot)
+}
+
+#[cfg(test)]
+fn untag_as<KV>(user: &str, channel: &String, line: &String, bot: &mut TagBot<KV>) -> Vec<String> where KV: db::KV<String, String> {
+    let untag_event = ChatEvent::ChannelMsg {
+        channel: channel.clone(),
+        from: user.to_owned(),
+        msg: format!("!untag #tag {}", hash(line)) };
+    match run_tag_bot_for_event(untag_event, bot) {
+        Some(Effect::Effect(ChatEffect::ChannelMsg { msg, .. })) => msg,
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn refuse_untag_by_others_test() {
+    let mut bot = test_bot();
+    let channel = "#my_channel".to_owned();
+    let line = "a test line #tag".to_owned();
+    run_tag_bot_for_line_in_channel(&UTC::now(), &channel, &"user1".to_owned(), &line, &mut bot);
+
+    let msg = untag_as("user2", &channel, &line, &mut bot);
+    assert_eq!(msg, vec!["user2: only user1, channel operators or admins may untag that line".to_owned()]);
+    assert!(bot.store.kv().get(&mk_key(&channel, &"#tag".to_owned(), &hash(&line))).unwrap().is_some());
+}
+
+#[test]
+fn allow_untag_by_operators_from_names_test() {
+    let mut bot = test_bot();
+    let channel = "#my_channel".to_owned();
+    let line = "a test line #tag".to_owned();
+    run_tag_bot_for_line_in_channel(&UTC::now(), &channel, &"user1".to_owned(), &line, &mut bot);
+
+    run_tag_bot_for_event(ChatEvent::ChannelNames { channel: channel.clone(), names: vec!["@op".to_owned(), "+voiced".to_owned(), "user2".to_owned()] }, &mut bot);
+    run_tag_bot_for_event(ChatEvent::ChannelNamesEnd { channel: channel.clone() }, &mut bot);
+    assert!(untag_as("user2", &channel, &line, &mut bot)[0].starts_with("user2: only"));
+    assert!(untag_as("op", &channel, &line, &mut bot)[0].starts_with("Removed tag #tag"));
+}
+
+#[test]
+fn allow_untag_by_voiced_from_mode_test() {
+    let mut bot = test_bot();
+    let channel = "#my_channel".to_owned();
+    let line = "a test line #tag".to_owned();
+    run_tag_bot_for_line_in_channel(&UTC::now(), &channel, &"user1".to_owned(), &line, &mut bot);
+
+    run_tag_bot_for_event(ChatEvent::ChannelMode { channel: channel.clone(), by: "op".to_owned(), modes: "+bv".to_owned(), args: vec!["*!*@spam".to_owned(), "user2".to_owned()] }, &mut bot);
+    run_tag_bot_for_event(ChatEvent::ChannelMode { channel: channel.clone(), by: "op".to_owned(), modes: "+o-o".to_owned(), args: vec!["user3".to_owned(), "user3".to_owned()] }, &mut bot);
+    assert!(untag_as("user3", &channel, &line, &mut bot)[0].starts_with("user3: only"));
+    assert!(untag_as("user2", &channel, &line, &mut bot)[0].starts_with("Removed tag #tag"));
+}
+
+#[test]
+fn allow_untag_by_admins_test() {
+    let mut bot = test_bot();
+    bot.config.admins = vec!["admin".to_owned()];
+    let channel = "#my_channel".to_owned();
+    let line = "a test line #tag".to_owned();
+    run_tag_bot_for_line_in_channel(&UTC::now(), &channel, &"user1".to_owned(), &line, &mut bot);
+
+    assert!(untag_as("admin", &channel, &line, &mut bot)[0].starts_with("Removed tag #tag"));
+}
+
+#[test]
+fn follow_members_across_nick_kick_and_quit_test() {
+    let mut bot = test_bot();
+    let channel = "#my_channel".to_owned();
+    let line = "a test line #tag".to_owned();
+    run_tag_bot_for_line_in_channel(&UTC::now(), &channel, &"user1".to_owned(), &line, &mut bot);
+
+    run_tag_bot_for_event(ChatEvent::ChannelNames { channel: channel.clone(), names: vec!["@op".to_owned(), "@op2".to_owned(), "@op3".to_owned()] }, &mut bot);
+    run_tag_bot_for_event(ChatEvent::ChannelNamesEnd { channel: channel.clone() }, &mut bot);
+    run_tag_bot_for_event(ChatEvent::NickChanged { old: "op".to_owned(), new: "renamed".to_owned() }, &mut bot);
+    run_tag_bot_for_event(ChatEvent::Kicked { channel: channel.clone(), who: "op2".to_owned(), by: "renamed".to_owned(), reason: None }, &mut bot);
+    run_tag_bot_for_event(ChatEvent::Quit { who: "op3".to_owned(), reason: Some("bye".to_owned()) }, &mut bot);
+    assert!(untag_as("op", &channel, &line, &mut bot)[0].starts_with("op: only"));
+    assert!(untag_as("op2", &channel, &line, &mut bot)[0].starts_with("op2: only"));
+    assert!(untag_as("op3", &channel, &line, &mut bot)[0].starts_with("op3: only"));
+    assert!(untag_as("renamed", &channel, &line, &mut bot)[0].starts_with("Removed tag #tag"));
+}
+
+#[test]
+fn tag_actions_test() {
+    let mut bot = test_bot();
+    let action = ChatEvent::ChannelAction { channel: "#rootmos".to_owned(), from: "user1".to_owned(), msg: "deploys v3 #deploy".to_owned() };
+    assert!(run_tag_bot_for_event(action, &mut bot).is_some());
+
+    let list = channel_msg_as("user2", "!list #deploy", &mut bot);
+    assert_eq!(list.len(), 2);
+    assert!(list[1].starts_with("* user1 deploys v3 #deploy"));
+
+    let command = ChatEvent::ChannelAction { channel: "#rootmos".to_owned(), from: "user1".to_owned(), msg: "!tags".to_owned() };
+    assert!(run_tag_bot_for_event(command, &mut bot).is_none());
+}
+
+#[test]
+fn undo_untag_test() {
+    let mut bot = test_bot();
+    let channel = "#my_channel".to_owned();
+    let line = "the deploy broke #tag".to_owned();
+    run_tag_bot_for_line_in_channel(&UTC::now(), &channel, &"user1".to_owned(), &line, &mut bot);
+    untag_as("user1", &channel, &line, &mut bot);
+
+    let key = mk_key(&channel, &"#tag".to_owned(), &hash(&line));
+    assert!(bot.store.kv().get(&key).unwrap().is_none());
+
+    let undo_event = ChatEvent::ChannelMsg { channel: channel.clone(), from: "user1".to_owned(), msg: "!undo".to_owned() };
+    match run_tag_bot_for_event(undo_event, &mut bot) {
+        Some(Effect::Effect(ChatEffect::ChannelMsg { msg, .. })) => assert_eq!(msg, vec![format!("Restored tag #tag on line: {}", line)]),
+        _ => panic!(),
+    }
+    assert!(bot.store.kv().get(&key).unwrap().is_some());
+    assert!(bot.store.kv().get(&mk_trash_key(&channel, &"#tag".to_owned(), &hash(&line))).unwrap().is_none());
+    assert!(!bot.store.kv().get_prefix(&mk_search_key_prefix(&channel, &"deploy".to_owned())).unwrap().is_empty());
+
+    let undo_event = ChatEvent::ChannelMsg { channel: channel.clone(), from: "user1".to_owned(), msg: "!undo".to_owned() };
+    match run_tag_bot_for_event(undo_event, &mut bot) {
+        Some(Effect::Effect(ChatEffect::ChannelMsg { msg, .. })) => assert_eq!(msg, vec!["user1: nothing to undo".to_owned()]),
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn restore_untagged_line_test() {
+    let mut bot = test_bot();
+    let channel = "#my_channel".to_owned();
+    let line = "a test line #tag".to_owned();
+    run_tag_bot_for_line_in_channel(&UTC::now(), &channel, &"user1".to_owned(), &line, &mut bot);
+    untag_as("user1", &channel, &line, &mut bot);
+
+    let restore_event = ChatEvent::ChannelMsg { channel: channel.clone(), from: "user2".to_owned(), msg: format!("!restore #tag {}", hash(&line)) };
+    match run_tag_bot_for_event(restore_event, &mut bot) {
+        Some(Effect::Effect(ChatEffect::ChannelMsg { msg, .. })) => assert!(msg[0].starts_with("user2: only user1")),
+        _ => panic!(),
+    }
+
+    let restore_event = ChatEvent::ChannelMsg { channel: channel.clone(), from: "user1".to_owned(), msg: format!("!restore #tag {}", hash(&line)) };
+    match run_tag_bot_for_event(restore_event, &mut bot) {
+        Some(Effect::Effect(ChatEffect::ChannelMsg { msg, .. })) => assert_eq!(msg, vec![format!("Restored tag #tag on line: {}", line)]),
+        _ => panic!(),
+    }
+    assert!(bot.store.kv().get(&mk_key(&channel, &"#tag".to_owned(), &hash(&line))).unwrap().is_some());
+}
+
+#[test]
+fn purge_old_trash_on_heartbeat_test() {
+    let mut bot = test_bot();
+    let channel = "#my_channel".to_owned();
+    let old_line = "an old line #tag".to_owned();
+    let new_line = "a new line #tag".to_owned();
+    run_tag_bot_for_line_in_channel(&UTC::now(), &channel, &"user1".to_owned(), &old_line, &mut bot);
+    run_tag_bot_for_line_in_channel(&UTC::now(), &channel, &"user1".to_owned(), &new_line, &mut bot);
+
+    let old_untag = ChatEvent::ChannelMsg { channel: channel.clone(), from: "user1".to_owned(), msg: format!("!untag #tag {}", hash(&old_line)) };
+    let an_hour_after_expiry = UTC::now() - Duration::hours(bot.config.trash_max_age_hours + 1);
+    tag_bot(Event::Event { time: an_hour_after_expiry, event: old_untag }, &mut bot);
+    untag_as("user1", &channel, &new_line, &mut bot);
+
+    assert_eq!(tag_bot(Event::Heartbeat { time: UTC::now() }, &mut bot), None);
+    assert!(bot.store.kv().get(&mk_trash_key(&channel, &"#tag".to_owned(), &hash(&old_line))).unwrap().is_none());
+    assert!(bot.store.kv().get(&mk_trash_key(&channel, &"#tag".to_owned(), &hash(&new_line))).unwrap().is_some());
+}
+
+#[cfg(test)]
+fn tag_cmd_result(cmd: &str, channel: &String, bot: &mut TagBot<db::hashmap_kv::HashMapKV>) -> Vec<String> {
+    let tag_event = ChatEvent::ChannelMsg { channel: channel.clone(), from: "tagger".to_owned(), msg: cmd.to_owned() };
+    match run_tag_bot_for_event(tag_event, bot) {
+        Some(Effect::Effect(ChatEffect::ChannelMsg { msg, .. })) => msg,
+        _ => panic!(),
+    }
+}
+
+#[cfg(test)]
+fn tagged_line(channel: &String, tag: &str, line: &str, bot: &TagBot<db::hashmap_kv::HashMapKV>) -> Option<TaggedLine> {
+    let key = mk_key(channel, &tag.to_owned(), &hash(&line.to_owned()));
+    bot.store.get(&key).unwrap()
+}
 
-    let tag_prefix = mk_key_prefix(&channel, &tag);
-    let mut tagged_lines = kv.get_prefix(&tag_prefix).iter().map(|p| serde_json::from_str(&p.1).unwrap()).collect::<Vec<TaggedLine>>();
-    tagged_lines.sort_by(|a, b| a.time.cmp(&b.time));
+#[test]
+fn tag_previous_message_test() {
+    let mut bot = test_bot();
+    let channel = "#my_channel".to_owned();
+    let time = UTC::now() - Duration::hours(1);
+    run_tag_bot_for_event(ChatEvent::ChannelMsg { channel: channel.clone(), from: "user1".to_owned(), msg: "first".to_owned() }, &mut bot);
+    tag_bot(Event::Event { time: time, event: ChatEvent::ChannelMsg { channel: channel.clone(), from: "user2".to_owned(), msg: "second".to_owned() } }, &mut bot);
 
-    let mut msg = tagged_lines.iter()
-        .map(|l| format!(
-                "{} (by: {}, at: {}, untag: \"!untag {} {}\")",
-                &l.line,
-                &l.user,
-                &l.time.with_timezone(&Local).to_rfc2822(),
-                &tag,
-                &l.hash))
-        .collect::<Vec<String>>();
-    msg.insert(0, format!("Listing tag {}:", tag));
-    ChatEffect::ChannelMsg { channel: channel, msg: msg }
+    let msg = tag_cmd_result("!tag #tag", &channel, &mut bot);
+    assert!(msg[0].contains(hash(&"second".to_owned()).as_str()));
+    let tl = tagged_line(&channel, "#tag", "second", &bot).unwrap();
+    assert_eq!(tl.user, "user2");
+    assert_eq!(tl.time, time);
 }
 
-fn tag_line<KV>(time: DateTime<UTC>, user: String, channel: String, tag: String, line: String, kv: &mut KV) -> ChatEffect where KV: db::KV<String, String> {
-    let line_hash = hash(&line);
-    let key = mk_key(&channel, &tag, &line_hash);
-    let tagged_line = TaggedLine {
-        channel: channel.clone(),
-        tag: tag.clone(),
-        time: time,
-        user: user,
-        line: line,
-        hash: line_hash.clone()};
-    let json = serde_json::to_string(&tagged_line).unwrap();
-    kv.put(&key, &json).unwrap();
-    let response = vec![format!("Line tagged, recall using: \"!list {}\", untag using \"!untag {} {}\"", tag, tag, line_hash)];
-    ChatEffect::ChannelMsg { channel: channel, msg: response }
-}
-
-fn untag_cmd<KV>(channel: String, tag: String, hash: String, kv: &mut KV) -> ChatEffect where KV: db::KV<String, String> {
-    let key = mk_key(&channel, &tag, &hash);
-    match kv.get(&key).unwrap() {
-        Some(json) => {
-            kv.remove(&key).unwrap();
-            let tl: TaggedLine = serde_json::from_str(json.as_str()).unwrap();
-            let msg = vec![format!("Removed tag {} from line: {}", tag, tl.line)];
-            ChatEffect::ChannelMsg { channel: channel, msg: msg }
-        },
-        None => {
-            let error = format!("Unable to find line tagged with {} and with hash {}", tag, hash);
-            ChatEffect::ChannelMsg { channel: channel, msg: vec![error] }
-        },
+#[test]
+fn tag_message_some_lines_back_test() {
+    let mut bot = test_bot();
+    let channel = "#my_channel".to_owned();
+    for line in vec!["first", "second", "third"] {
+        run_tag_bot_for_event(ChatEvent::ChannelMsg { channel: channel.clone(), from: "user1".to_owned(), msg: line.to_owned() }, &mut bot);
     }
+
+    tag_cmd_result("!tag #tag ^3", &channel, &mut bot);
+    assert!(tagged_line(&channel, "#tag", "first", &bot).is_some());
+    assert_eq!(tag_cmd_result("!tag #tag ^4", &channel, &mut bot), vec![format!("No line 4 messages back in {} to tag", channel)]);
 }
 
 #[test]
-fn save_line_without_tag_test() {
-    let mut kv = db::hashmap_kv::HashMapKV::new();
-    let input = Event::Event { time: UTC::now(), event: ChatEvent::ChannelMsg {
-        channel: "my_channel".to_owned(),
-        from: "user1".to_owned(),
-        msg: "test line tag".to_owned() } };
+fn tag_last_message_from_nick_test() {
+    let mut bot = test_bot();
+    let channel = "#my_channel".to_owned();
+    run_tag_bot_for_event(ChatEvent::ChannelMsg { channel: channel.clone(), from: "user1".to_owned(), msg: "from user1".to_owned() }, &mut bot);
+    run_tag_bot_for_event(ChatEvent::ChannelMsg { channel: channel.clone(), from: "user2".to_owned(), msg: "from user2".to_owned() }, &mut bot);
 
-    assert_eq!(tag_bot(input, &mut kv), None)
+    tag_cmd_result("!tag #tag user1", &channel, &mut bot);
+    assert_eq!(tagged_line(&channel, "#tag", "from user1", &bot).unwrap().user, "user1");
+    assert_eq!(tag_cmd_result("!tag #tag user3", &channel, &mut bot), vec![format!("No recent line from user3 in {} to tag", channel)]);
+    assert!(tagged_line(&channel, "#tag", "user3", &bot).is_none());
 }
 
 #[test]
-fn ignore_line_with_almost_tags_test() {
-    let mut kv = db::hashmap_kv::HashMapKV::new();
-    let input = Event::Event { time: UTC::now(), event: ChatEvent::ChannelMsg {
-        channel: "my_channel".to_owned(),
-        from: "user1".to_owned(),
-        msg: "not#tag #not(a-tag)".to_owned() } };
+fn tag_explicit_text_test() {
+    let mut bot = test_bot();
+    let channel = "#my_channel".to_owned();
+    tag_cmd_result("!tag #tag remember the milk", &channel, &mut bot);
+    assert_eq!(tagged_line(&channel, "#tag", "remember the milk", &bot).unwrap().user, "tagger");
+}
 
-    assert_eq!(tag_bot(input, &mut kv), None)
+#[test]
+fn forget_lines_beyond_recent_capacity_test() {
+    let mut bot = test_bot();
+    bot.config.recent_lines = 2;
+    let channel = "#my_channel".to_owned();
+    assert_eq!(tag_cmd_result("!tag #tag", &channel, &mut bot), vec![format!("No recent line in {} to tag", channel)]);
+    for line in vec!["first", "second", "!list #tag", "third"] {
+        run_tag_bot_for_event(ChatEvent::ChannelMsg { channel: channel.clone(), from: "user1".to_owned(), msg: line.to_owned() }, &mut bot);
+    }
+    assert_eq!(bot.recent[&channel].iter().map(|l| l.msg.clone()).collect::<Vec<String>>(), vec!["second".to_owned(), "third".to_owned()]);
 }
 
 #[test]
-fn save_line_with_tag_in_the_middle_test() {
+fn skip_unreadable_records_in_listings_test() {
+    let mut bot = test_bot();
+    let channel = "#my_channel".to_owned();
     let tag = "#tag".to_owned();
-    save_line_with_tag_test(format!("test line {} some more text", tag), tag)
+    let line = "a test line #tag".to_owned();
+    run_tag_bot_for_line_in_channel(&UTC::now(), &channel, &"user1".to_owned(), &line, &mut bot);
+    let bad_key = mk_key(&channel, &tag, &"deadbeef".to_owned());
+    bot.store.kv_mut().put(&bad_key, &"{ not json".to_owned()).unwrap();
+    bot.store.kv_mut().put(&mk_time_key(&channel, &tag, &UTC::now(), &"deadbeef".to_owned()), &bad_key).unwrap();
+
+    let recall_event = ChatEvent::ChannelMsg { channel: channel.clone(), from: "user2".to_owned(), msg: format!("!list {}", tag) };
+    match run_tag_bot_for_event(recall_event, &mut bot) {
+        Some(Effect::Effect(ChatEffect::ChannelMsg { msg, .. })) => {
+            assert_eq!(msg.len(), 3);
+            assert!(msg[1].contains(line.as_str()));
+            assert_eq!(msg[2], "Skipped 1 unreadable records".to_owned());
+        },
+        _ => panic!(),
+    }
+
+    let tags_event = ChatEvent::ChannelMsg { channel: channel.clone(), from: "user2".to_owned(), msg: "!tags".to_owned() };
+    match run_tag_bot_for_event(tags_event, &mut bot) {
+        Some(Effect::Effect(ChatEffect::ChannelMsg { msg, .. })) => {
+            assert!(msg[1].starts_with("#tag (1 lines"));
+            assert_eq!(msg[2], "Skipped 1 unreadable records".to_owned());
+        },
+        _ => panic!(),
+    }
 }
 
 #[test]
-fn save_line_with_tag_at_the_end_test() {
+fn retag_same_line_test() {
+    let mut bot = test_bot();
+    let channel = "#my_channel".to_owned();
     let tag = "#tag".to_owned();
-    save_line_with_tag_test(format!("another test line {}", tag), tag)
+    let line = "a test line #tag".to_owned();
+    run_tag_bot_for_line_in_channel(&(UTC::now() - Duration::hours(1)), &channel, &"user1".to_owned(), &line, &mut bot);
+    run_tag_bot_for_line_in_channel(&UTC::now(), &channel, &"user2".to_owned(), &line, &mut bot);
+    assert_eq!(line_count(&channel, &tag, &bot.store), Ok(1));
+
+    let recall_event = ChatEvent::ChannelMsg { channel: channel.clone(), from: "user3".to_owned(), msg: format!("!list {}", tag) };
+    match run_tag_bot_for_event(recall_event, &mut bot) {
+        Some(Effect::Effect(ChatEffect::ChannelMsg { msg, .. })) => {
+            assert_eq!(msg.len(), 2);
+            assert!(msg[1].contains("by: user2"));
+        },
+        _ => panic!(),
+    }
+
+    assert!(untag_as("user2", &channel, &line, &mut bot)[0].starts_with("Removed tag #tag"));
+    assert_eq!(bot.store.kv().scan(db::Scan::prefix(mk_time_prefix())).count(), 0);
+    assert_eq!(bot.store.kv().scan(db::Scan::prefix(mk_count_prefix())).count(), 0);
 }
 
 #[test]
-fn save_line_with_tag_at_the_start_test() {
+fn drop_stale_time_entries_when_counting_lines_test() {
+    let mut bot = test_bot();
+    let channel = "#my_channel".to_owned();
     let tag = "#tag".to_owned();
-    save_line_with_tag_test(format!("{} yet another test line", tag), tag)
-}
+    let line = "a test line #tag".to_owned();
+    run_tag_bot_for_line_in_channel(&UTC::now(), &channel, &"user1".to_owned(), &line, &mut bot);
+    let stale = mk_time_key(&channel, &tag, &(UTC::now() - Duration::hours(1)), &hash(&line));
+    bot.store.kv_mut().put(&stale, &mk_key(&channel, &tag, &hash(&line))).unwrap();
+    bot.store.kv_mut().remove(&mk_count_key(&channel, &tag)).unwrap();
 
+    assert_eq!(count_lines(&mut bot.store), Ok(2));
+    assert_eq!(bot.store.kv().get(&stale), Ok(None));
+    assert_eq!(line_count(&channel, &tag, &bot.store), Ok(1));
+}
 
-#[cfg(test)]
-fn save_line_with_tag_test(line: String, tag: String) {
-    let mut kv = db::hashmap_kv::HashMapKV::new();
-    let channel = "my_channel".to_owned();
-    let input = Event::Event { time: UTC::now(), event: ChatEvent::ChannelMsg {
-        channel: channel.clone(),
-        from: "user1".to_owned(),
-        msg: line.clone() } };
+#[test]
+fn report_unreadable_record_on_untag_test() {
+    let mut bot = test_bot();
+    let channel = "#my_channel".to_owned();
+    bot.store.kv_mut().put(&mk_key(&channel, &"#tag".to_owned(), &"deadbeef".to_owned()), &"{ not json".to_owned()).unwrap();
 
-    match tag_bot(input, &mut kv) {
+    let untag_event = ChatEvent::ChannelMsg { channel: channel.clone(), from: "user1".to_owned(), msg: "!untag #tag deadbeef".to_owned() };
+    match run_tag_bot_for_event(untag_event, &mut bot) {
         Some(Effect::Effect(ChatEffect::ChannelMsg { channel: to_channel, msg })) => {
             assert_eq!(to_channel, channel);
-            assert!(msg[0].contains(hash(&line).as_str()));
+            assert_eq!(msg, vec!["Sorry, that failed: unreadable record".to_owned()]);
         },
         _ => panic!(),
     }
+}
 
-    let expected_key = format!("{}-{}-{}", channel, tag, hash(&line));
-    match kv.get(&expected_key).unwrap() {
-        Some(raw_data) => {
-            let tagged_line: TaggedLine = serde_json::from_str(&raw_data).unwrap();
-            assert_eq!(tagged_line.line, line)
-        },
+#[test]
+fn backfill_time_index_test() {
+    let mut bot = test_bot();
+    let channel = "#my_channel".to_owned();
+    let tag = "#tag".to_owned();
+    let time = UTC::now();
+    let tl = TaggedLine { channel: channel.clone(), tag: tag.clone(), user: "user1".to_owned(), time: time, line: "old #tag".to_owned(), hash: "abcd1234".to_owned() };
+    bot.store.put(&mk_key(&channel, &tag, &tl.hash), &tl).unwrap();
+
+    assert_eq!(backfill_time_index(&mut bot.store).unwrap(), 1);
+    assert_eq!(bot.store.kv().get(&mk_time_key(&channel, &tag, &time, &tl.hash)).unwrap(), Some(mk_key(&channel, &tag, &tl.hash)));
+    assert_eq!(backfill_time_index(&mut bot.store).unwrap(), 0);
+
+    let recall_event = ChatEvent::ChannelMsg { channel: channel.clone(), from: "user2".to_owned(), msg: format!("!list {}", tag) };
+    match run_tag_bot_for_event(recall_event, &mut bot) {
+        Some(Effect::Effect(ChatEffect::ChannelMsg { msg, .. })) => assert!(msg[1].starts_with("old #tag")),
         _ => panic!(),
     }
 }
 
 #[test]
-fn recall_tag_one_line_test() {
-    let mut kv = db::hashmap_kv::HashMapKV::new();
-    let channel = "my_channel".to_owned();
-    let tag = "#tag".to_owned();
-    let line = format!("a test line {}", tag);
+fn migrate_keys_test() {
+    let mut bot = test_bot();
+    let channel = "#my_channel".to_owned();
+    let time = UTC::now();
+    let foo = TaggedLine { channel: channel.clone(), tag: "#foo".to_owned(), user: "user1".to_owned(), time: time, line: "deploy #foo".to_owned(), hash: "abcd1234".to_owned() };
+    let foo_bar = TaggedLine { channel: channel.clone(), tag: "#foo-bar".to_owned(), user: "user1".to_owned(), time: time, line: "other #foo-bar".to_owned(), hash: "1234abcd".to_owned() };
+    let trashed = TrashedLine { line: foo_bar.clone(), deleted_by: "user2".to_owned(), deleted_at: time };
+    for (key, value) in vec![
+        ("#my_channel-#foo-abcd1234".to_owned(), serde_json::to_string(&foo).unwrap()),
+        ("#my_channel-#foo-bar-1234abcd".to_owned(), serde_json::to_string(&foo_bar).unwrap()),
+        ("trash-#my_channel-#foo-bar-1234abcd".to_owned(), serde_json::to_string(&trashed).unwrap()),
+        ("time-#my_channel-#foo-000000000001.000000000-abcd1234".to_owned(), "#my_channel-#foo-abcd1234".to_owned()),
+        ("search-#my_channel-deploy-#foo-abcd1234".to_owned(), "#my_channel-#foo-abcd1234".to_owned()),
+        ("meta-time-index".to_owned(), time.to_rfc3339()),
+        ("#my_channel-#foo-deadbeef".to_owned(), "{ not json".to_owned())] {
+        bot.store.kv_mut().put(&key, &value).unwrap();
+    }
 
-    let input_line = Event::Event { time: UTC::now(), event: ChatEvent::ChannelMsg {
-        channel: channel.clone(),
-        from: "user1".to_owned(),
-        msg: line.clone() } };
-    match tag_bot(input_line, &mut kv) {
-        Some(_) => (),
+    assert_eq!(migrate_keys(&mut bot.store).unwrap(), 3);
+    let legacy = bot.store.kv().scan(db::Scan::all()).map(|pair| pair.unwrap().0).filter(|key| !key.contains('\u{0}')).collect::<Vec<String>>();
+    assert_eq!(legacy, vec!["#my_channel-#foo-deadbeef".to_owned()]);
+    assert_eq!(bot.store.get::<TaggedLine>(&mk_key(&channel, &foo.tag, &foo.hash)).unwrap().map(|tl| tl.line), Some(foo.line.clone()));
+    assert!(bot.store.get::<TrashedLine>(&mk_trash_key(&channel, &foo_bar.tag, &foo_bar.hash)).unwrap().is_some());
+    assert!(bot.store.kv().get(&mk_meta_key("time-index")).unwrap().is_some());
+    assert_eq!(migrate_keys(&mut bot.store).unwrap(), 0);
+
+    let list_event = ChatEvent::ChannelMsg { channel: channel.clone(), from: "user2".to_owned(), msg: "!list #foo".to_owned() };
+    match run_tag_bot_for_event(list_event, &mut bot) {
+        Some(Effect::Effect(ChatEffect::ChannelMsg { msg, .. })) => {
+            assert_eq!(msg.len(), 2);
+            assert!(msg[1].starts_with("deploy #foo"))
+        },
+        _ => panic!(),
+    }
+    let search_event = ChatEvent::ChannelMsg { channel: channel.clone(), from: "user2".to_owned(), msg: "!search deploy".to_owned() };
+    match run_tag_bot_for_event(search_event, &mut bot) {
+        Some(Effect::Effect(ChatEffect::ChannelMsg { msg, .. })) => assert!(msg[1].starts_with("deploy #foo")),
         _ => panic!(),
     }
+}
 
-    let recall_cmdline = format!("!list {}", tag);
-    let recall_event = Event::Event { time: UTC::now(), event: ChatEvent::ChannelMsg {
-        channel: channel.clone(),
-        from: "user2".to_owned(),
-        msg: recall_cmdline } };
+#[cfg(test)]
+fn load_fixture(json: &str) -> TagBot<db::hashmap_kv::HashMapKV> {
+    let mut bot = test_bot();
+    let pairs: Vec<(String, String)> = serde_json::from_str(json).unwrap();
+    for (key, value) in pairs {
+        bot.store.kv_mut().put(&key, &value).unwrap();
+    }
+    bot
+}
 
-    match tag_bot(recall_event, &mut kv) {
-        Some(Effect::Effect(ChatEffect::ChannelMsg { channel: to_channel, msg })) => {
-            assert_eq!(to_channel, channel);
-            assert!(msg[0].contains(tag.as_str()));
-            assert!(msg[1].contains(line.as_str()));
-        },
+#[cfg(test)]
+fn channel_msg_as<KV>(from: &str, msg: &str, bot: &mut TagBot<KV>) -> Vec<String> where KV: db::KV<String, String> {
+    let event = ChatEvent::ChannelMsg { channel: "#rootmos".to_owned(), from: from.to_owned(), msg: msg.to_owned() };
+    match run_tag_bot_for_event(event, bot) {
+        Some(Effect::Effect(ChatEffect::ChannelMsg { msg, .. })) => msg,
         _ => panic!(),
     }
 }
 
 #[test]
-fn recall_tag_several_lines_test() {
-    let mut kv = db::hashmap_kv::HashMapKV::new();
-    let channel = "my_channel".to_owned();
-    let tag = "#tag".to_owned();
+fn order_migrations_by_version_test() {
+    let versions = migrations::<db::hashmap_kv::HashMapKV>().iter().map(|m| m.version).collect::<Vec<u32>>();
+    assert_eq!(versions, (1..versions.len() as u32 + 1).collect::<Vec<u32>>());
+}
 
-    let line1 = format!("a test line {}", tag);
-    let time1 = UTC::now() - Duration::hours(3);
-    let user1 = "user1".to_owned();
-    let hash1 = hash(&line1);
-    run_tag_bot_for_line_in_channel(&time1, &channel, &user1, &line1, &mut kv);
+#[test]
+fn migrate_baseline_fixture_test() {
+    let mut bot = load_fixture(include_str!("../fixtures/tag_bot_db_baseline.json"));
+    let applied = migrate::run(&mut bot.store, &migrations()).unwrap();
+    assert_eq!(applied.iter().map(|a| (a.version, a.records)).collect::<Vec<(u32, usize)>>(), vec![(1, 3), (2, 3), (3, 2)]);
+    assert_eq!(migrate::schema_version(&bot.store), Ok(3));
+    assert_eq!(migrate::run(&mut bot.store, &migrations()), Ok(vec![]));
 
-    let line2 = format!("another {} test line", tag);
-    let time2 = time1 + Duration::hours(1);
-    let user2 = "user2".to_owned();
-    let hash2 = hash(&line2);
-    run_tag_bot_for_line_in_channel(&time2, &channel, &user2, &line2, &mut kv);
+    let list = channel_msg_as("user1", "!list #deploy", &mut bot);
+    assert_eq!(list.len(), 3);
+    assert!(list[1].starts_with("rolled out #deploy v1"));
+    assert!(list[2].starts_with("#deploy v2 broke staging"));
+    assert!(channel_msg_as("user1", "!search staging", &mut bot)[1].starts_with("#deploy v2 broke staging"));
+    assert_eq!(channel_msg_as("user1", "!tags", &mut bot).len(), 3);
+}
 
-    let line3 = format!("{} yet another line", tag);
-    let time3 = time2 + Duration::hours(1);
-    let user3 = "user3".to_owned();
-    let hash3 = hash(&line3);
-    run_tag_bot_for_line_in_channel(&time3, &channel, &user3, &line3, &mut kv);
+#[test]
+fn migrate_indexed_fixture_test() {
+    let mut bot = load_fixture(include_str!("../fixtures/tag_bot_db_indexed.json"));
+    migrate::run(&mut bot.store, &migrations()).unwrap();
+    let legacy = bot.store.kv().scan(db::Scan::all()).map(|pair| pair.unwrap().0).filter(|key| !key.contains('\u{0}'));
+    assert_eq!(legacy.count(), 0);
 
-    assert!(time1 < time2);
-    assert!(time2 < time3);
+    let list = channel_msg_as("user1", "!list #deploy", &mut bot);
+    assert_eq!(list.len(), 3);
+    assert!(list[1].starts_with("rolled out #deploy v1"));
+    assert_eq!(channel_msg_as("alice", "!restore #idea 9e8d7c6b", &mut bot), vec!["Restored tag #idea on line: #idea cache the search index".to_owned()]);
+    assert!(channel_msg_as("user1", "!list #idea", &mut bot)[1].starts_with("#idea cache the search index"));
+}
 
-    let recall_cmdline = format!("!list {}", tag);
-    let recall_event = Event::Event { time: UTC::now(), event: ChatEvent::ChannelMsg {
-        channel: channel.clone(),
-        from: "user4".to_owned(),
-        msg: recall_cmdline } };
+#[test]
+fn migrate_dry_run_test() {
+    let bot = load_fixture(include_str!("../fixtures/tag_bot_db_indexed.json"));
+    let applied = migrate::dry_run(&bot.store, &migrations()).unwrap();
+    assert_eq!(applied.iter().map(|a| a.version).collect::<Vec<u32>>(), vec![1, 2, 3]);
+    assert_eq!(migrate::schema_version(&bot.store), Ok(0));
+    assert!(bot.store.kv().get(&"#rootmos-#deploy-1f2e3d4c".to_owned()).unwrap().is_some());
+}
 
-    match tag_bot(recall_event, &mut kv) {
-        Some(Effect::Effect(ChatEffect::ChannelMsg { channel: to_channel, msg })) => {
-            assert_eq!(to_channel, channel);
-            assert_eq!(msg[0], format!("Listing tag {}:", tag));
+#[test]
+fn export_and_import_test() {
+    let mut bot = test_bot();
+    let channel = "#my_channel".to_owned();
+    tag_lines_hourly(3, &channel, &"#tag".to_owned(), &mut bot);
+    run_tag_bot_for_line_in_channel(&UTC::now(), &"#other".to_owned(), &"user1".to_owned(), &"elsewhere #tag".to_owned(), &mut bot);
 
-            assert!(msg[1].contains(line1.as_str()));
-            assert!(msg[1].contains(user1.as_str()));
-            assert!(msg[1].contains(&time1.with_timezone(&Local).to_rfc2822()));
-            assert!(msg[1].contains(hash1.as_str()));
+    let mut exported = Vec::new();
+    assert_eq!(export_lines(&bot.store, &ExportFilter::default(), &mut exported).unwrap(), 4);
 
-            assert!(msg[2].contains(line2.as_str()));
-            assert!(msg[2].contains(user2.as_str()));
-            assert!(msg[2].contains(&time2.with_timezone(&Local).to_rfc2822()));
-            assert!(msg[2].contains(hash2.as_str()));
+    let mut copy = test_bot();
+    assert_eq!(import_lines(&mut copy.store, &exported[..]).unwrap(), 4);
+    assert_eq!(import_lines(&mut copy.store, &exported[..]).unwrap(), 0);
 
-            assert!(msg[3].contains(line3.as_str()));
-            assert!(msg[3].contains(user3.as_str()));
-            assert!(msg[3].contains(&time3.with_timezone(&Local).to_rfc2822()));
-            assert!(msg[3].contains(hash3.as_str()));
+    let list_event = ChatEvent::ChannelMsg { channel: channel.clone(), from: "user2".to_owned(), msg: "!list #tag".to_owned() };
+    match run_tag_bot_for_event(list_event, &mut copy) {
+        Some(Effect::Effect(ChatEffect::ChannelMsg { msg, .. })) => {
+            assert_eq!(msg.len(), 4);
+            assert!(msg[1].starts_with("line 0 #tag"))
         },
         _ => panic!(),
     }
 }
 
 #[test]
-fn untag_nonexisting_line_comlains_test() {
-    let mut kv = db::hashmap_kv::HashMapKV::new();
-    let channel = "my_channel".to_owned();
-    let tag = "#tag".to_owned();
+fn export_filtered_lines_test() {
+    let mut bot = test_bot();
+    let channel = "#my_channel".to_owned();
+    let start = tag_lines_hourly(4, &channel, &"#tag".to_owned(), &mut bot);
+    tag_lines_hourly(2, &channel, &"#other".to_owned(), &mut bot);
+    tag_lines_hourly(2, &"#elsewhere".to_owned(), &"#tag".to_owned(), &mut bot);
 
-    let nonexisting_hash = "abbababe";
-    let untag_cmdline = format!("!untag {} {}", tag, nonexisting_hash);
-    let untag_event = Event::Event { time: UTC::now(), event: ChatEvent::ChannelMsg {
-        channel: channel.clone(),
-        from: "user3".to_owned(),
-        msg: untag_cmdline } };
+    let count = |filter: ExportFilter| export_lines(&bot.store, &filter, &mut Vec::<u8>::new()).unwrap();
+    assert_eq!(count(ExportFilter::default()), 8);
+    assert_eq!(count(ExportFilter { channel: Some(channel.clone()), .. ExportFilter::default() }), 6);
+    assert_eq!(count(ExportFilter { tag: Some("#tag".to_owned()), .. ExportFilter::default() }), 6);
+    assert_eq!(count(ExportFilter { channel: Some(channel.clone()), tag: Some("#tag".to_owned()), .. ExportFilter::default() }), 4);
+    assert_eq!(count(ExportFilter {
+        channel: Some(channel.clone()),
+        tag: Some("#tag".to_owned()),
+        since: Some(start + Duration::hours(1)),
+        until: Some(start + Duration::hours(3)) }), 2);
+}
 
-    match tag_bot(untag_event, &mut kv) {
-        Some(Effect::Effect(ChatEffect::ChannelMsg { msg, .. })) => {
-            let error = format!("Unable to find line tagged with {} and with hash {}", tag, nonexisting_hash);
-            assert_eq!(msg[0], error);
-        },
+#[test]
+fn import_nothing_from_malformed_export_test() {
+    let mut bot = test_bot();
+    let input = "{\"channel\":\"#c\",\"tag\":\"#t\",\"user\":\"u\",\"time\":\"2016-10-01T12:00:00Z\",\"line\":\"l #t\",\"hash\":\"abcd1234\"}\n{ not json\n";
+    match import_lines(&mut bot.store, input.as_bytes()) {
+        Err(BotError::Storage(db::Error::Encoding(err))) => assert!(err.contains("line 2")),
         _ => panic!(),
     }
+    assert_eq!(bot.store.kv().scan(db::Scan::all()).count(), 0);
 }
 
 #[test]
-fn untag_line_test() {
-    let mut kv = db::hashmap_kv::HashMapKV::new();
-    let channel = "my_channel".to_owned();
-    let tag = "#tag".to_owned();
+fn snapshot_test() {
+    extern crate tempdir;
+    let dir = tempdir::TempDir::new("tag_bot_snapshot_test").unwrap();
+    let mut bot = test_bot();
+    tag_lines_hourly(2, &"#my_channel".to_owned(), &"#tag".to_owned(), &mut bot);
+    let config = Config { snapshot_dir: Some(dir.path().to_str().unwrap().to_owned()), snapshot_keep: 2, .. Config::default() };
 
-    let line1 = format!("a test line {}", tag);
-    let time1 = UTC::now() - Duration::hours(3);
-    let user1 = "user1".to_owned();
-    let line1_hash = hash(&line1);
-    run_tag_bot_for_line_in_channel(&time1, &channel, &user1, &line1, &mut kv);
+    let mut state = Snapshots::default();
+    let start = UTC::now();
+    for i in 0..6 {
+        let now = start + Duration::hours(12 * i);
+        snapshot(now, &config, &mut state, &bot.store).unwrap();
+        state.finish(true).unwrap();
+    }
+    assert_eq!(state.last, Some(start + Duration::hours(48)));
 
-    let expected_key = mk_key(&channel, &tag, &line1_hash);
-    assert!(kv.get(&expected_key).unwrap().is_some());
+    let mut snapshots = fs::read_dir(dir.path()).unwrap().map(|e| e.unwrap().path()).collect::<Vec<_>>();
+    snapshots.sort();
+    assert_eq!(snapshots.len(), 2);
+    let copy = db::log_kv::LogKV::open(&snapshots[1]).unwrap();
+    let pairs = |kv: &KV<String, String>| kv.scan(db::Scan::all()).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(pairs(&copy), pairs(bot.store.kv()));
+    assert!(pairs(&copy).iter().any(|&(ref key, _)| key.starts_with(&mk_count_prefix())));
 
-    let untag_cmdline = format!("!untag {} {}", tag, line1_hash);
-    let untag_event = Event::Event { time: UTC::now(), event: ChatEvent::ChannelMsg {
-        channel: channel.clone(),
-        from: "user3".to_owned(),
-        msg: untag_cmdline } };
+    let mut restored = Store::new(db::hashmap_kv::HashMapKV::new());
+    assert_eq!(restore_from(&snapshots[1], &mut restored), Ok(pairs(bot.store.kv()).len()));
+    assert_eq!(pairs(restored.kv()), pairs(bot.store.kv()));
+}
 
-    match tag_bot(untag_event, &mut kv) {
-        Some(Effect::Effect(ChatEffect::ChannelMsg { msg, .. })) => {
-            assert_eq!(msg[0], format!("Removed tag {} from line: {}", tag, line1));
+#[test]
+fn parse_date_test() {
+    assert_eq!(parse_date("2016-11-05"), Ok(UTC.ymd(2016, 11, 5).and_hms(0, 0, 0)));
+    assert_eq!(parse_date("2016-11-05T12:30:00+01:00"), Ok(UTC.ymd(2016, 11, 5).and_hms(11, 30, 0)));
+    assert!(parse_date("yesterday").is_err());
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn query_tagged_lines_in_sqlite_test() {
+    use rootmos_bot::db::sqlite_kv::{SqliteKV, LineQuery};
+    let mut bot = TagBot::new(SqliteKV::open_in_memory(index_sqlite_line).unwrap(), Config::default());
+    let channel = "#my_channel".to_owned();
+    let time = UTC::now();
+    run_tag_bot_for_line_in_channel(&time, &channel, &"user1".to_owned(), &"deploy went fine #ops".to_owned(), &mut bot);
+    run_tag_bot_for_line_in_channel(&time, &channel, &"user2".to_owned(), &"deploy broke staging #ops #incident".to_owned(), &mut bot);
+    run_tag_bot_for_line_in_channel(&time, &channel, &"user2".to_owned(), &"lunch #food".to_owned(), &mut bot);
+
+    let found = bot.store.kv().query(&LineQuery::new().channel(&channel).user("user2").text("deploy")).unwrap();
+    let mut tags = found.iter().map(|&(ref key, ref json)| typed::decode::<TaggedLine>(key, json).unwrap().tag).collect::<Vec<String>>();
+    tags.sort();
+    assert_eq!(tags, vec!["#incident", "#ops"]);
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn list_and_untag_in_sqlite_test() {
+    use rootmos_bot::db::sqlite_kv::SqliteKV;
+    let mut bot = TagBot::new(SqliteKV::open_in_memory(index_sqlite_line).unwrap(), Config::default());
+    let channel = "#rootmos".to_owned();
+    let line = "deploy went fine #tag".to_owned();
+    run_tag_bot_for_line_in_channel(&UTC::now(), &channel, &"user1".to_owned(), &line, &mut bot);
+    run_tag_bot_for_line_in_channel(&UTC::now(), &"#other".to_owned(), &"user1".to_owned(), &"elsewhere #tag".to_owned(), &mut bot);
+
+    let list = channel_msg_as("user2", "!list #tag", &mut bot);
+    assert_eq!(list.len(), 2);
+    assert!(list[1].starts_with(&line));
+    assert!(channel_msg_as("user2", "!tags", &mut bot)[1].starts_with("#tag (1 lines"));
+
+    assert!(untag_as("user1", &channel, &line, &mut bot)[0].starts_with("Removed tag #tag"));
+    assert_eq!(channel_msg_as("user2", "!list #tag", &mut bot).len(), 1);
+}
+
+#[cfg(test)]
+struct FailingKV;
+
+#[cfg(test)]
+impl db::KV<String, String> for FailingKV {
+    fn put(&mut self, _: &String, _: &String) -> Result<(), db::Error> { Err(db::Error::Io("disk on fire".to_owned())) }
+    fn get(&self, _: &String) -> Result<Option<String>, db::Error> { Err(db::Error::Io("disk on fire".to_owned())) }
+    fn scan<'a>(&'a self, _: db::Scan<String>) -> db::ScanIter<'a, String, String> { Box::new(Some(Err(db::Error::Io("disk on fire".to_owned()))).into_iter()) }
+    fn remove(&mut self, _: &String) -> Result<(), db::Error> { Err(db::Error::Io("disk on fire".to_owned())) }
+    fn write(&mut self, _: db::Batch<String, String>) -> Result<(), db::Error> { Err(db::Error::Io("disk on fire".to_owned())) }
+}
+
+#[test]
+fn report_storage_errors_test() {
+    let mut bot = TagBot::new(FailingKV, Config::default());
+    let channel = "#my_channel".to_owned();
+    for cmd in vec!["a line #tag", "!list #tag", "!tags", "!search line", "!untag #tag deadbeef"] {
+        let event = ChatEvent::ChannelMsg { channel: channel.clone(), from: "user1".to_owned(), msg: cmd.to_owned() };
+        match run_tag_bot_for_event(event, &mut bot) {
+            Some(Effect::Effect(ChatEffect::ChannelMsg { msg, .. })) => assert_eq!(msg, vec!["Sorry, that failed: storage error".to_owned()]),
+            _ => panic!(),
         }
-        _ => panic!(),
     }
-
-    let expected_key = mk_key(&channel, &tag, &line1_hash);
-    assert!(kv.get(&expected_key).unwrap().is_none());
+    assert_eq!(tag_bot(Event::Heartbeat { time: UTC::now() }, &mut bot), None);
 }
 
 #[cfg(test)]
-fn run_tag_bot_for_line_in_channel<KV>(time: &DateTime<UTC>, channel: &String, user: &String, line: &String, kv: &mut KV) -> () where KV: db::KV<String, String> {
+fn run_tag_bot_for_line_in_channel<KV>(time: &DateTime<UTC>, channel: &String, user: &String, line: &String, bot: &mut TagBot<KV>) -> () where KV: db::KV<String, String> {
     let input = Event::Event { time: time.clone(), event: ChatEvent::ChannelMsg {
         channel: channel.clone(),
         from: user.clone(),
         msg: line.clone() } };
-    match tag_bot(input, kv) {
+    match tag_bot(input, bot) {
         Some(_) => (),
         _ => panic!(),
     }
 }
 
 
+fn flag_value(args: &[String], flag: &str) -> Option<String> {
+    args.iter().position(|arg| arg == flag).and_then(|i| args.get(i + 1)).cloned()
+}
+
+fn parse_date(s: &str) -> Result<DateTime<UTC>, String> {
+    DateTime::parse_from_rfc3339(s).map(|t| t.with_timezone(&UTC))
+        .or_else(|_| NaiveDate::parse_from_str(s, "%Y-%m-%d").map(|d| UTC.from_utc_datetime(&d.and_hms(0, 0, 0))))
+        .map_err(|err| format!("Unable to parse date {}: {}", s, err))
+}
+
+fn date_flag(args: &[String], flag: &str) -> Option<DateTime<UTC>> {
+    flag_value(args, flag).map(|s| match parse_date(&s) {
+        Ok(date) => date,
+        Err(err) => {
+            let _ = writeln!(io::stderr(), "{}", err);
+            std::process::exit(2)
+        },
+    })
+}
+
+fn export_to<KV>(path: Option<String>, filter: &ExportFilter, store: &Store<KV>) -> Result<usize, BotError> where KV: db::KV<String, String> {
+    match path {
+        Some(path) => {
+            let mut out = BufWriter::new(try!(File::create(&path)));
+            let exported = try!(export_lines(store, filter, &mut out));
+            try!(out.flush());
+            Ok(exported)
+        },
+        None => {
+            let stdout = io::stdout();
+            let mut out = stdout.lock();
+            export_lines(store, filter, &mut out)
+        },
+    }
+}
+
+fn import_from<KV>(path: Option<String>, store: &mut Store<KV>) -> Result<usize, BotError> where KV: db::KV<String, String> {
+    match path {
+        Some(path) => import_lines(store, BufReader::new(try!(File::open(&path)))),
+        None => {
+            let stdin = io::stdin();
+            let input = stdin.lock();
+            import_lines(store, input)
+        },
+    }
+}
+
+fn restore_from<KV>(path: &Path, store: &mut Store<KV>) -> Result<usize, db::Error> where KV: db::KV<String, String> {
+    let snapshot = try!(db::log_kv::LogKV::open(path));
+    db::copy(&snapshot, store.kv_mut())
+}
+
 fn main() {
-    let kv = db::rocksdb_kv::RocksDBKV::new(Path::new("tag_bot_db"));
-    rootmos_bot::irc::run("irc-config.json", tag_bot, kv);
+    let config = Config::load(Path::new("tag-bot-config.json"));
+    match config.db_backend.as_str() {
+        "rocksdb" => start_rocksdb(config),
+        "log" => {
+            let path = config.db_path.clone().unwrap_or("tag_bot_db.log".to_owned());
+            match db::log_kv::LogKV::open(Path::new(&path)) {
+                Ok(kv) => start(TagBot::new(kv, config)),
+                Err(err) => panic!("Unable to open {}: {}", path, err),
+            }
+        },
+        "sqlite" => start_sqlite(config),
+        other => panic!("Unknown db_backend {}, expected rocksdb, log or sqlite", other),
+    }
+}
+
+#[cfg(feature = "rocksdb")]
+fn start_rocksdb(config: Config) {
+    let path = config.db_path.clone().unwrap_or("tag_bot_db".to_owned());
+    match db::rocksdb_kv::RocksDBKV::new(Path::new(&path)) {
+        Ok(kv) => start(TagBot::new(kv, config)),
+        Err(err) => panic!("Unable to open {}: {}", path, err),
+    }
+}
+
+#[cfg(not(feature = "rocksdb"))]
+fn start_rocksdb(_: Config) {
+    panic!("Built without RocksDB support, set db_backend to \"log\" in tag-bot-config.json")
+}
+
+#[cfg(feature = "sqlite")]
+fn start_sqlite(config: Config) {
+    let path = config.db_path.clone().unwrap_or("tag_bot_db.sqlite".to_owned());
+    match db::sqlite_kv::SqliteKV::open(Path::new(&path), index_sqlite_line) {
+        Ok(kv) => start(TagBot::new(kv, config)),
+        Err(err) => panic!("Unable to open {}: {}", path, err),
+    }
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn start_sqlite(_: Config) {
+    panic!("Built without SQLite support, rebuild with --features sqlite")
+}
+
+#[cfg(feature = "sqlite")]
+fn convert_to_sqlite<KV>(kv: &KV, path: &str) -> Result<usize, db::Error> where KV: db::KV<String, String> {
+    let mut sqlite = try!(db::sqlite_kv::SqliteKV::open(Path::new(path), index_sqlite_line));
+    db::copy(kv, &mut sqlite)
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn convert_to_sqlite<KV>(_: &KV, _: &str) -> Result<usize, db::Error> where KV: db::KV<String, String> {
+    panic!("Built without SQLite support, rebuild with --features sqlite")
+}
+
+fn start<KV>(mut bot: TagBot<KV>) where KV: db::KV<String, String> + Send + 'static {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let cmd = args.first().cloned().unwrap_or(String::new());
+
+    match cmd.as_str() {
+        "" | "migrate" | "export" | "import" | "convert" | "restore" => (),
+        other => {
+            let _ = writeln!(io::stderr(), "Unknown command {}, expected migrate, export, import, convert or restore", other);
+            std::process::exit(2)
+        },
+    }
+
+    if cmd == "migrate" && args.iter().any(|arg| arg == "--dry-run") {
+        match migrate::dry_run(&bot.store, &migrations()) {
+            Ok(applied) => report_migrations(&applied),
+            Err(err) => panic!("Migration dry run failed: {}", err),
+        }
+        return
+    }
+
+    if cmd == "restore" {
+        let input = flag_value(&args, "--input").expect("restore needs --input SNAPSHOT");
+        if !Path::new(&input).is_file() {
+            panic!("No snapshot at {}", input)
+        }
+        if bot.store.kv().scan(db::Scan::all().limit(1)).next().is_some() {
+            panic!("Refusing to restore into a non-empty tag_bot_db")
+        }
+        match restore_from(Path::new(&input), &mut bot.store) {
+            Ok(n) => { let _ = writeln!(io::stderr(), "Restored {} keys from {}", n, input); },
+            Err(err) => panic!("Restore failed: {}", err),
+        }
+    }
+
+    match migrate::run(&mut bot.store, &migrations()) {
+        Ok(applied) => report_migrations(&applied),
+        Err(err) => panic!("Unable to migrate tag_bot_db: {}", err),
+    }
+
+    match cmd.as_str() {
+        "migrate" | "restore" => (),
+        "export" => {
+            let filter = ExportFilter {
+                channel: flag_value(&args, "--channel"),
+                tag: flag_value(&args, "--tag"),
+                since: date_flag(&args, "--since"),
+                until: date_flag(&args, "--until"),
+            };
+            match export_to(flag_value(&args, "--output"), &filter, &bot.store) {
+                Ok(n) => { let _ = writeln!(io::stderr(), "Exported {} tagged lines", n); },
+                Err(err) => panic!("Export failed: {:?}", err),
+            }
+        },
+        "convert" => {
+            let output = flag_value(&args, "--output").expect("convert needs --output PATH");
+            let result = match flag_value(&args, "--to").unwrap_or("sqlite".to_owned()).as_str() {
+                "log" => db::log_kv::LogKV::open(Path::new(&output)).and_then(|mut kv| db::copy(bot.store.kv(), &mut kv)),
+                "sqlite" => convert_to_sqlite(bot.store.kv(), &output),
+                other => panic!("Unknown convert target {}, expected log or sqlite", other),
+            };
+            match result {
+                Ok(n) => { let _ = writeln!(io::stderr(), "Copied {} keys to {}", n, output); },
+                Err(err) => panic!("Convert failed: {}", err),
+            }
+        },
+        "import" => match import_from(flag_value(&args, "--input"), &mut bot.store) {
+            Ok(n) => { let _ = writeln!(io::stderr(), "Imported {} new tagged lines", n); },
+            Err(err) => panic!("Import failed: {:?}", err),
+        },
+        "" => {
+            let limits = bot.config.flood_limits();
+            rootmos_bot::irc::run("irc-config.json", limits, tag_bot, bot)
+        },
+        _ => unreachable!(),
+    }
 }
@@ -0,0 +1,226 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Limits {
+    pub burst: u32,
+    pub rate: Duration,
+    pub max_queue: usize,
+    pub max_age: Duration,
+    pub short_reply_lines: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits { burst: 4, rate: Duration::from_secs(2), max_queue: 50, max_age: Duration::from_secs(120), short_reply_lines: 2 }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+    pub queued: usize,
+    pub deepest: usize,
+    pub sent: u64,
+    pub dropped: u64,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} queued (deepest {}), {} sent, {} dropped", self.queued, self.deepest, self.sent, self.dropped)
+    }
+}
+
+struct Queued {
+    line: String,
+    at: Instant,
+}
+
+struct Target {
+    tokens: f64,
+    refilled: Instant,
+    short: VecDeque<Queued>,
+    long: VecDeque<Queued>,
+    dropped: usize,
+}
+
+fn secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 / 1e9
+}
+
+impl Target {
+    fn new(limits: &Limits, now: Instant) -> Target {
+        Target { tokens: limits.burst as f64, refilled: now, short: VecDeque::new(), long: VecDeque::new(), dropped: 0 }
+    }
+
+    fn len(&self) -> usize {
+        self.short.len() + self.long.len()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.len() == 0 && self.dropped == 0
+    }
+
+    fn tokens_at(&self, now: Instant, limits: &Limits) -> f64 {
+        let earned = secs(now.duration_since(self.refilled)) / secs(limits.rate);
+        (self.tokens + earned).min(limits.burst as f64)
+    }
+
+    fn drop_oldest(&mut self) {
+        if self.long.pop_front().or_else(|| self.short.pop_front()).is_some() {
+            self.dropped += 1
+        }
+    }
+}
+
+pub struct Outbox {
+    limits: Limits,
+    targets: HashMap<String, Target>,
+    stats: Stats,
+}
+
+impl Outbox {
+    pub fn new(limits: Limits) -> Outbox {
+        Outbox { limits: limits, targets: HashMap::new(), stats: Stats::default() }
+    }
+
+    pub fn push(&mut self, target: &str, lines: Vec<String>, now: Instant) {
+        let limits = &self.limits;
+        let t = self.targets.entry(target.to_owned()).or_insert_with(|| Target::new(limits, now));
+        {
+            let queue = if lines.len() <= limits.short_reply_lines { &mut t.short } else { &mut t.long };
+            queue.extend(lines.into_iter().map(|line| Queued { line: line, at: now }));
+        }
+        while t.len() > limits.max_queue {
+            t.drop_oldest();
+            self.stats.dropped += 1
+        }
+        self.stats.deepest = self.stats.deepest.max(t.len())
+    }
+
+    pub fn pop_ready(&mut self, now: Instant) -> Vec<(String, String)> {
+        let mut ready = Vec::new();
+        for (target, t) in self.targets.iter_mut() {
+            t.tokens = t.tokens_at(now, &self.limits);
+            t.refilled = now;
+
+            for queue in vec![&mut t.short, &mut t.long] {
+                while queue.front().map_or(false, |q| now.duration_since(q.at) > self.limits.max_age) {
+                    queue.pop_front();
+                    t.dropped += 1;
+                    self.stats.dropped += 1
+                }
+            }
+
+            while t.tokens >= 1.0 {
+                let line = if t.dropped > 0 {
+                    let note = format!("[{} lines dropped]", t.dropped);
+                    t.dropped = 0;
+                    note
+                } else {
+                    match t.short.pop_front().or_else(|| t.long.pop_front()) {
+                        Some(q) => q.line,
+                        None => break,
+                    }
+                };
+                t.tokens -= 1.0;
+                self.stats.sent += 1;
+                ready.push((target.clone(), line))
+            }
+        }
+
+        let idle: Vec<String> = self.targets.iter()
+            .filter(|&(_, t)| t.is_idle() && t.tokens >= self.limits.burst as f64)
+            .map(|(target, _)| target.clone()).collect();
+        for target in idle {
+            self.targets.remove(&target);
+        }
+        ready
+    }
+
+    pub fn next_ready(&self, now: Instant) -> Option<Duration> {
+        self.targets.values().filter(|t| !t.is_idle()).map(|t| {
+            let missing = 1.0 - t.tokens_at(now, &self.limits);
+            if missing <= 0.0 { 0 } else { (missing * secs(self.limits.rate) * 1000.0).ceil() as u64 }
+        }).min().map(Duration::from_millis)
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats { queued: self.targets.values().map(Target::len).sum(), .. self.stats.clone() }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use outbox::*;
+    use std::time::{Duration, Instant};
+
+    fn limits() -> Limits {
+        Limits { burst: 2, rate: Duration::from_secs(1), max_queue: 5, max_age: Duration::from_secs(60), short_reply_lines: 1 }
+    }
+
+    fn lines(prefix: &str, n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("{}{}", prefix, i)).collect()
+    }
+
+    #[test]
+    fn release_burst_then_rate_test() {
+        let start = Instant::now();
+        let mut outbox = Outbox::new(limits());
+        outbox.push("#chan", lines("line", 4), start);
+        assert_eq!(outbox.pop_ready(start), vec![("#chan".to_owned(), "line0".to_owned()), ("#chan".to_owned(), "line1".to_owned())]);
+        assert_eq!(outbox.pop_ready(start), vec![]);
+        assert_eq!(outbox.next_ready(start), Some(Duration::from_secs(1)));
+        assert_eq!(outbox.pop_ready(start + Duration::from_millis(1500)), vec![("#chan".to_owned(), "line2".to_owned())]);
+        assert_eq!(outbox.pop_ready(start + Duration::from_secs(2)), vec![("#chan".to_owned(), "line3".to_owned())]);
+        assert_eq!(outbox.next_ready(start + Duration::from_secs(2)), None);
+    }
+
+    #[test]
+    fn limit_targets_separately_test() {
+        let start = Instant::now();
+        let mut outbox = Outbox::new(limits());
+        outbox.push("#a", lines("a", 3), start);
+        outbox.push("#b", lines("b", 3), start);
+        assert_eq!(outbox.pop_ready(start).len(), 4);
+        assert_eq!(outbox.stats().queued, 2);
+    }
+
+    #[test]
+    fn send_short_replies_first_test() {
+        let start = Instant::now();
+        let mut outbox = Outbox::new(limits());
+        outbox.push("#chan", lines("list", 4), start);
+        outbox.pop_ready(start);
+        outbox.push("#chan", vec!["reply".to_owned()], start);
+        assert_eq!(outbox.pop_ready(start + Duration::from_secs(1)), vec![("#chan".to_owned(), "reply".to_owned())]);
+        assert_eq!(outbox.pop_ready(start + Duration::from_secs(2)), vec![("#chan".to_owned(), "list2".to_owned())]);
+    }
+
+    #[test]
+    fn drop_oldest_long_output_when_too_deep_test() {
+        let start = Instant::now();
+        let mut outbox = Outbox::new(limits());
+        outbox.push("#chan", vec!["reply".to_owned()], start);
+        outbox.push("#chan", lines("list", 7), start);
+        assert_eq!(outbox.stats(), Stats { queued: 5, deepest: 5, sent: 0, dropped: 3 });
+        assert_eq!(outbox.pop_ready(start), vec![
+            ("#chan".to_owned(), "[3 lines dropped]".to_owned()),
+            ("#chan".to_owned(), "reply".to_owned())]);
+        assert_eq!(outbox.pop_ready(start + Duration::from_secs(1)), vec![("#chan".to_owned(), "list3".to_owned())]);
+    }
+
+    #[test]
+    fn drop_stale_lines_test() {
+        let start = Instant::now();
+        let mut outbox = Outbox::new(limits());
+        outbox.push("#chan", lines("old", 4), start);
+        outbox.pop_ready(start);
+        let later = start + Duration::from_secs(61);
+        outbox.push("#chan", vec!["new".to_owned()], later);
+        assert_eq!(outbox.pop_ready(later), vec![
+            ("#chan".to_owned(), "[2 lines dropped]".to_owned()),
+            ("#chan".to_owned(), "new".to_owned())]);
+        assert_eq!(outbox.stats(), Stats { queued: 0, deepest: 4, sent: 4, dropped: 2 });
+    }
+}
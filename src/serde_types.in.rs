@@ -1,5 +1,5 @@
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 struct TaggedLine {
     channel: String,
     tag: String,
@@ -8,3 +8,46 @@ struct TaggedLine {
     line: String,
     hash: String,
 }
+
+#[derive(Serialize, Deserialize, Debug)]
+struct TrashedLine {
+    line: TaggedLine,
+    deleted_by: String,
+    deleted_at: chrono::DateTime<UTC>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Config {
+    #[serde(default="default_page_size")]
+    page_size: usize,
+    #[serde(default)]
+    newest_first: bool,
+    #[serde(default)]
+    private_threshold: Option<usize>,
+    #[serde(default)]
+    admins: Vec<String>,
+    #[serde(default="default_trash_max_age_hours")]
+    trash_max_age_hours: i64,
+    #[serde(default="default_recent_lines")]
+    recent_lines: usize,
+    #[serde(default)]
+    snapshot_dir: Option<String>,
+    #[serde(default="default_snapshot_interval_hours")]
+    snapshot_interval_hours: i64,
+    #[serde(default="default_snapshot_keep")]
+    snapshot_keep: usize,
+    #[serde(default="default_db_backend")]
+    db_backend: String,
+    #[serde(default)]
+    db_path: Option<String>,
+    #[serde(default="default_flood_burst")]
+    flood_burst: u32,
+    #[serde(default="default_flood_rate_ms")]
+    flood_rate_ms: u64,
+    #[serde(default="default_flood_max_queue")]
+    flood_max_queue: usize,
+    #[serde(default="default_flood_max_age_secs")]
+    flood_max_age_secs: u64,
+    #[serde(default="default_flood_short_reply_lines")]
+    flood_short_reply_lines: usize,
+}